@@ -0,0 +1,532 @@
+use axum::{
+    extract::{Json, Path, Request, State as AxumState},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Router,
+};
+use futures::StreamExt;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::{debug, error, info, warn};
+
+use crate::channel::{ChannelError, ConnInfo};
+use crate::handler::terminate_conn;
+use crate::metrics::METRICS;
+use crate::revocation::{revoke, Revocation};
+use crate::websocket::{close_agent, State};
+
+/// redis channel of broadcasts from the HTTP API, every node relays them to its agents
+pub const NODE_BROADCAST_TOPIC: &str = "node:broadcast";
+
+/// body of `POST /api/channels/{topic}/broadcast`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastRequest {
+    pub event: String,
+
+    #[serde(default)]
+    pub payload: serde_json::Value,
+
+    /// agent id (`{conn_id}:{channel}:{join_ref}`) that does not receive the broadcast
+    #[serde(default)]
+    pub exclude: Option<String>,
+}
+
+/// one item of `POST /api/broadcast`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicBroadcast {
+    pub topic: String,
+
+    #[serde(flatten)]
+    pub broadcast: BroadcastRequest,
+}
+
+/// published to redis, so that the other nodes broadcast to their agents too
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeBroadcast {
+    pub node: String,
+
+    #[serde(flatten)]
+    pub broadcast: TopicBroadcast,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BroadcastResult {
+    pub topic: String,
+    pub event: String,
+    pub delivered: usize, // agents on this node
+    pub fanout: bool,     // published to the other nodes
+}
+
+/// HTTP API, authenticated with `Authorization: Bearer {api_key}`
+pub fn router(state: Arc<State>) -> Router<Arc<State>> {
+    Router::new()
+        .route("/api/channels/{topic}/broadcast", post(broadcast))
+        .route("/api/broadcast", post(broadcast_many))
+        .route("/api/admin/channels", get(admin_channels))
+        .route("/api/admin/channels/{topic}", get(admin_channel).delete(admin_channel_rm))
+        .route("/api/admin/channels/{topic}/agents/{agent_id}", delete(admin_agent_kick))
+        .route("/api/admin/connections", get(admin_connections))
+        .route("/api/admin/connections/{conn_id}", delete(admin_conn_disconnect))
+        .route("/api/admin/revocations", post(admin_revoke))
+        .route_layer(middleware::from_fn_with_state(state, require_api_key))
+}
+
+/// whether the request carries `Authorization: Bearer {api_key}`, never without an api key
+///
+/// compared in constant time, so that the time taken doesn't tell how much of a guess is right
+pub(crate) fn has_api_key(state: &State, headers: &HeaderMap) -> bool {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (state.api_key.as_deref(), bearer) {
+        (Some(api_key), Some(bearer)) => api_key.as_bytes().ct_eq(bearer.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+async fn require_api_key(AxumState(state): AxumState<Arc<State>>, request: Request, next: Next) -> Response {
+    if state.api_key.is_none() {
+        warn!("API / no api key configured, {} rejected", request.uri());
+        return (StatusCode::UNAUTHORIZED, "API disabled").into_response();
+    }
+    if !has_api_key(&state, request.headers()) {
+        warn!("API / unauthorized request {}", request.uri());
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    next.run(request).await
+}
+
+async fn broadcast(
+    Path(topic): Path<String>, AxumState(state): AxumState<Arc<State>>, Json(broadcast): Json<BroadcastRequest>,
+) -> Json<BroadcastResult> {
+    Json(broadcast_across_nodes(state, TopicBroadcast { topic, broadcast }).await)
+}
+
+async fn broadcast_many(AxumState(state): AxumState<Arc<State>>, Json(broadcasts): Json<Vec<TopicBroadcast>>) -> Json<Vec<BroadcastResult>> {
+    let mut results = Vec::with_capacity(broadcasts.len());
+    for broadcast in broadcasts {
+        results.push(broadcast_across_nodes(state.clone(), broadcast).await);
+    }
+    Json(results)
+}
+
+/// broadcast to the agents of this node, then publish to the other nodes
+pub async fn broadcast_across_nodes(state: Arc<State>, topic_broadcast: TopicBroadcast) -> BroadcastResult {
+    let delivered = broadcast_local(&state, &topic_broadcast).await;
+
+    let node_broadcast = NodeBroadcast {
+        node: state.node_id.clone(),
+        broadcast: topic_broadcast,
+    };
+    let message = serde_json::to_string(&node_broadcast).unwrap();
+    let fanout = match state.redis_client.get_multiplexed_async_connection().await {
+        Ok(mut redis_conn) => {
+            let result: RedisResult<usize> = redis_conn.publish(NODE_BROADCAST_TOPIC, message).await;
+            result
+                .inspect_err(|e| {
+                    METRICS.redis_publish_failures.inc();
+                    error!("API / fail to publish to redis: {}", e)
+                })
+                .is_ok()
+        }
+        Err(e) => {
+            error!("API / fail to get redis connection: {}", e);
+            false
+        }
+    };
+
+    let TopicBroadcast { topic, broadcast } = node_broadcast.broadcast;
+    info!("API / broadcast {}:{}, delivered: {}, fanout: {}", topic, broadcast.event, delivered, fanout);
+    BroadcastResult {
+        topic,
+        event: broadcast.event,
+        delivered,
+        fanout,
+    }
+}
+
+/// broadcast to the agents of this node, returns the number of agents who received it
+pub async fn broadcast_local(state: &State, topic_broadcast: &TopicBroadcast) -> usize {
+    let TopicBroadcast { topic, broadcast } = topic_broadcast;
+    let result = state
+        .ctl
+        .lock()
+        .await
+        .channel_broadcast_json_from(topic, &broadcast.event, broadcast.payload.clone(), broadcast.exclude.clone())
+        .await;
+    match result {
+        Ok(count) => count,
+        Err(ChannelError::ChannelNotFound) | Err(ChannelError::ChannelEmpty) => 0, // no agents on this node
+        Err(e) => {
+            error!("API / fail to broadcast to {}: {}", topic, e);
+            0
+        }
+    }
+}
+
+/// `GET /api/admin/channels`, channels of this node with their agent counts
+async fn admin_channels(AxumState(state): AxumState<Arc<State>>) -> Json<serde_json::Value> {
+    let ctl = state.ctl.lock().await;
+    let channels = ctl.channels.lock().await;
+    let mut items = vec![];
+    for (name, channel) in channels.iter() {
+        items.push(json!({"name": name, "agents": channel.agents.lock().await.len(), "listener": channel.listener_status()}));
+    }
+    items.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    Json(json!(items))
+}
+
+/// `GET /api/admin/channels/{topic}`, agents and presence of a channel
+async fn admin_channel(Path(topic): Path<String>, AxumState(state): AxumState<Arc<State>>) -> Result<Json<serde_json::Value>, StatusCode> {
+    let ctl = state.ctl.lock().await;
+    let listener = match ctl.channels.lock().await.get(&topic) {
+        Some(channel) => channel.listener_status(),
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+    let mut agents = ctl
+        .agents
+        .lock()
+        .await
+        .values()
+        .filter(|agent| agent.channel == topic)
+        .map(|agent| json!({"id": agent.id, "external_id": agent.external_id, "meta": agent.presence_meta()}))
+        .collect::<Vec<_>>();
+    agents.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+    let presence = ctl.channel_presence(&topic).await;
+    Ok(Json(json!({"name": topic, "listener": listener, "agents": agents, "presence": presence})))
+}
+
+/// `DELETE /api/admin/channels/{topic}`, every agent is closed like a kicked one, then the channel is removed with `channel_rm`
+async fn admin_channel_rm(Path(topic): Path<String>, AxumState(state): AxumState<Arc<State>>) -> StatusCode {
+    let agent_ids = match state.ctl.lock().await.channels.lock().await.get(&topic) {
+        Some(channel) => channel.agents.lock().await.clone(),
+        None => return StatusCode::NOT_FOUND,
+    };
+    for agent_id in agent_ids.iter() {
+        close_agent(&state, agent_id, &topic, "removed").await;
+    }
+    // the last agent removes the channel, unless it's special
+    let ctl = state.ctl.lock().await;
+    if ctl.channel_exists(&topic).await {
+        ctl.channel_rm(topic.clone()).await;
+    }
+    info!("API / channel {} removed, {} agents closed", topic, agent_ids.len());
+    StatusCode::NO_CONTENT
+}
+
+/// `DELETE /api/admin/channels/{topic}/agents/{agent_id}`, the agent leaves the channel like on `phx_leave`
+async fn admin_agent_kick(Path((topic, agent_id)): Path<(String, String)>, AxumState(state): AxumState<Arc<State>>) -> StatusCode {
+    match state.ctl.lock().await.agents.lock().await.get(&agent_id) {
+        Some(agent) if agent.channel == topic => {}
+        _ => return StatusCode::NOT_FOUND,
+    };
+    close_agent(&state, &agent_id, &topic, "kicked").await;
+    info!("API / agent {} kicked from {}", agent_id, topic);
+    StatusCode::NO_CONTENT
+}
+
+/// `GET /api/admin/connections`, websocket and long-poll connections of this node
+async fn admin_connections(AxumState(state): AxumState<Arc<State>>) -> Json<serde_json::Value> {
+    let ctl = state.ctl.lock().await;
+    let agents = ctl.agents.lock().await;
+    let items = ctl
+        .conn_list()
+        .await
+        .into_iter()
+        .map(|conn_info| {
+            let prefix = format!("{}:", conn_info.conn_id);
+            let mut conn_agents = agents
+                .keys()
+                .filter(|agent_id| agent_id.starts_with(&prefix))
+                .cloned()
+                .collect::<Vec<_>>();
+            conn_agents.sort();
+            conn_json(&conn_info, conn_agents)
+        })
+        .collect::<Vec<_>>();
+    Json(json!(items))
+}
+
+fn conn_json(conn_info: &ConnInfo, agents: Vec<String>) -> serde_json::Value {
+    json!({
+        "id": conn_info.conn_id,
+        "transport": conn_info.transport,
+        "remote_addr": conn_info.remote_addr.map(|addr| addr.to_string()),
+        "connected_at": conn_info.connected_at.to_rfc3339(),
+        "last_heartbeat": conn_info.last_heartbeat.map(|at| at.to_rfc3339()),
+        "client_identity": conn_info.client_identity.as_ref().map(|client_identity| &client_identity.name),
+        "agents": agents,
+    })
+}
+
+/// `DELETE /api/admin/connections/{conn_id}`, the connection is closed by `conn_cleanup`, its agents leave their channels
+async fn admin_conn_disconnect(Path(conn_id): Path<String>, AxumState(state): AxumState<Arc<State>>) -> StatusCode {
+    if !state.ctl.lock().await.conn_exists(&conn_id).await {
+        return StatusCode::NOT_FOUND;
+    }
+    terminate_conn(&state, &conn_id, "closed").await;
+    state.ctl.lock().await.conn_cleanup(conn_id.clone()).await;
+    info!("API / connection {} disconnected", conn_id);
+    StatusCode::NO_CONTENT
+}
+
+/// `POST /api/admin/revocations`, `{"jti": "...", "exp": ...}` or `{"user": "..."}`, stored in redis and applied by every node
+async fn admin_revoke(AxumState(state): AxumState<Arc<State>>, Json(revocation): Json<Revocation>) -> Response {
+    match revoke(state, revocation.clone()).await {
+        Ok(disconnected) => {
+            info!("API / {:?} revoked, {} connections closed", revocation, disconnected);
+            Json(json!({"disconnected": disconnected})).into_response()
+        }
+        Err(e) => {
+            error!("API / fail to revoke {:?}: {}", revocation, e);
+            (StatusCode::SERVICE_UNAVAILABLE, "redis unavailable").into_response()
+        }
+    }
+}
+
+/// relay broadcasts from the HTTP API of the other nodes
+pub async fn listen_to_node_broadcast(state: Arc<State>) -> RedisResult<()> {
+    let mut redis_pubsub = state.redis_client.get_async_pubsub().await?;
+    redis_pubsub.subscribe(NODE_BROADCAST_TOPIC).await?;
+    let mut redis_pubsub_stream = redis_pubsub.on_message();
+    info!("NODE_BROADCAST / subscribed to redis, node: {}", state.node_id);
+
+    while let Some(stream_message) = redis_pubsub_stream.next().await {
+        let payload: String = stream_message.get_payload()?;
+        let node_broadcast = match serde_json::from_str::<NodeBroadcast>(&payload) {
+            Ok(node_broadcast) => node_broadcast,
+            Err(e) => {
+                warn!("NODE_BROADCAST / fail to deserialize, {}, payload: `{}`", e, payload);
+                continue;
+            }
+        };
+        if node_broadcast.node == state.node_id {
+            continue; // already broadcast by the API handler
+        }
+        let delivered = broadcast_local(&state, &node_broadcast.broadcast).await;
+        debug!("NODE_BROADCAST / from {}, {}, delivered: {}", node_broadcast.node, node_broadcast.broadcast.topic, delivered);
+    }
+
+    error!("NODE_BROADCAST / redis stream ended");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::ChannelMessage;
+    use axum::body::Body;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    fn test_state() -> Arc<State> {
+        let redis_client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+        Arc::new(State {
+            api_key: Some("key".into()),
+            ..State::new(redis_client, "secret".into())
+        })
+    }
+
+    fn post(uri: &str, api_key: Option<&str>, body: serde_json::Value) -> Request {
+        let mut request = axum::http::Request::post(uri).header(header::CONTENT_TYPE, "application/json");
+        if let Some(api_key) = api_key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", api_key));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    async fn response_json(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_requires_api_key() {
+        let state = test_state();
+        let app = router(state.clone()).with_state(state);
+
+        let body = json!({"event": "e", "payload": {}});
+        let response = app
+            .clone()
+            .oneshot(post("/api/channels/room/broadcast", None, body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for wrong in ["wrong", "ke", "keys", ""] {
+            let response = app
+                .clone()
+                .oneshot(post("/api/channels/room/broadcast", Some(wrong), body.clone()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", wrong);
+        }
+
+        let response = app.oneshot(post("/api/channels/room/broadcast", Some("key"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response_json(response).await["delivered"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_api_broadcast_exclude() {
+        let state = test_state();
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.channel_add("room".into(), None).await;
+            for agent_id in ["c1:room:1", "c2:room:1"] {
+                ctl.agent_add(agent_id.into(), None).await;
+                ctl.channel_join("room", agent_id.into(), agent_id.into()).await.unwrap();
+            }
+        }
+        let mut rx1 = state.ctl.lock().await.agent_rx("c1:room:1".into()).await.unwrap();
+        let mut rx2 = state.ctl.lock().await.agent_rx("c2:room:1".into()).await.unwrap();
+
+        let app = router(state.clone()).with_state(state.clone());
+        let body = json!({"event": "news", "payload": {"n": 1}, "exclude": "c1:room:1"});
+        let response = app.oneshot(post("/api/channels/room/broadcast", Some("key"), body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let result = response_json(response).await;
+        assert_eq!(result["topic"], "room");
+        assert_eq!(result["event"], "news");
+
+        let ChannelMessage::Reply(reply) = tokio::time::timeout(std::time::Duration::from_secs(1), rx2.recv())
+            .await
+            .unwrap()
+            .unwrap()
+        else {
+            panic!("agents receive replies")
+        };
+        assert_eq!(reply.event, "news");
+        assert_eq!(serde_json::to_value(&reply.payload).unwrap(), json!({"n": 1}));
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(rx1.try_recv().is_err(), "excluded agent should not receive the broadcast");
+    }
+
+    #[tokio::test]
+    async fn test_api_broadcast_many() {
+        let state = test_state();
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.channel_add("a".into(), None).await;
+            ctl.agent_add("c1:a:1".into(), None).await;
+            ctl.channel_join("a", "c1:a:1".into(), "alice".into()).await.unwrap();
+        }
+
+        let app = router(state.clone()).with_state(state);
+        let body = json!([
+            {"topic": "a", "event": "e1", "payload": 1},
+            {"topic": "b", "event": "e2", "payload": 2},
+        ]);
+        let response = app.oneshot(post("/api/broadcast", Some("key"), body)).await.unwrap();
+        let results = response_json(response).await;
+        assert_eq!(results[0]["topic"], "a");
+        assert_eq!(results[0]["delivered"], 1);
+        assert_eq!(results[1]["topic"], "b");
+        assert_eq!(results[1]["delivered"], 0);
+    }
+
+    fn get(uri: &str) -> Request {
+        axum::http::Request::get(uri)
+            .header(header::AUTHORIZATION, "Bearer key")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn delete(uri: &str) -> Request {
+        axum::http::Request::delete(uri)
+            .header(header::AUTHORIZATION, "Bearer key")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_admin_channels_and_kick() {
+        let state = test_state();
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.channel_add("room".into(), None).await;
+            ctl.conn_add("c1".into(), "websocket", None).await;
+            for agent_id in ["c1:room:1", "c2:room:1"] {
+                ctl.agent_add(agent_id.into(), None).await;
+                ctl.channel_join("room", agent_id.into(), "alice".into()).await.unwrap();
+            }
+        }
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+        let app = router(state.clone()).with_state(state.clone());
+
+        let response = app.clone().oneshot(get("/api/admin/channels")).await.unwrap();
+        assert_eq!(response_json(response).await, json!([{"name": "room", "agents": 2, "listener": "none"}]));
+
+        let response = app.clone().oneshot(get("/api/admin/channels/room")).await.unwrap();
+        let channel = response_json(response).await;
+        assert_eq!(channel["agents"][0]["id"], "c1:room:1");
+        assert_eq!(channel["presence"]["alice"]["metas"].as_array().unwrap().len(), 2);
+
+        let response = app.clone().oneshot(get("/api/admin/channels/missing")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.clone().oneshot(delete("/api/admin/channels/room/agents/c1:room:1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let ChannelMessage::Reply(close) = conn_rx.try_recv().unwrap() else {
+            panic!("connections receive replies")
+        };
+        assert_eq!((close.event.as_str(), close.join_ref.as_deref()), ("phx_close", Some("1")));
+        assert!(!state.ctl.lock().await.agents.lock().await.contains_key("c1:room:1"));
+
+        let response = app.clone().oneshot(delete("/api/admin/channels/room/agents/c1:room:1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.oneshot(delete("/api/admin/channels/room")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!state.ctl.lock().await.channel_exists("room").await);
+        assert!(state.ctl.lock().await.agents.lock().await.is_empty(), "removed with agent_rm");
+        assert!(state.ctl.lock().await.agent_rx("c2:room:1".into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_admin_connections() {
+        let state = test_state();
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.conn_add("c1".into(), "websocket", Some("127.0.0.1:4000".parse().unwrap())).await;
+            ctl.conn_heartbeat("c1").await;
+            ctl.channel_add("room".into(), None).await;
+            ctl.agent_add("c1:room:1".into(), None).await;
+            ctl.channel_join("room", "c1:room:1".into(), "alice".into()).await.unwrap();
+        }
+        let app = router(state.clone()).with_state(state.clone());
+
+        let response = app.clone().oneshot(get("/api/admin/connections")).await.unwrap();
+        let conns = response_json(response).await;
+        assert_eq!(conns[0]["id"], "c1");
+        assert_eq!(conns[0]["transport"], "websocket");
+        assert_eq!(conns[0]["remote_addr"], "127.0.0.1:4000");
+        assert!(conns[0]["last_heartbeat"].is_string());
+        assert_eq!(conns[0]["agents"], json!(["c1:room:1"]));
+
+        let response = app.clone().oneshot(delete("/api/admin/connections/c1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.ctl.lock().await.conn_list().await.is_empty());
+        assert!(state.ctl.lock().await.agents.lock().await.is_empty());
+
+        let response = app.oneshot(delete("/api/admin/connections/c1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_api_node_broadcast_format() {
+        let node_broadcast: NodeBroadcast =
+            serde_json::from_value(json!({"node": "n1", "topic": "room", "event": "e", "payload": {"k": "v"}})).unwrap();
+        assert_eq!(node_broadcast.node, "n1");
+        assert_eq!(node_broadcast.broadcast.topic, "room");
+        assert_eq!(node_broadcast.broadcast.broadcast.payload, json!({"k": "v"}));
+        assert_eq!(node_broadcast.broadcast.broadcast.exclude, None);
+    }
+}
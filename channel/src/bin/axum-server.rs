@@ -1,102 +1,82 @@
-use axum::{
-    extract::{Json, Query, State as AxumState, WebSocketUpgrade},
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::{get, post},
-    Router,
-};
 use channel::{
-    channel::ChannelControl,
-    utils::{generate_jwt, random_string},
-    websocket::{add_channel, axum_on_connected, datetime_handler, launch_channel_redis_listen_task, State},
+    config::{ConfigFile, Reloadable},
+    inbound::MessageLimits,
+    jwt::JwtConfig,
+    listen::{ListenerConfig, RouteSet},
+    origin::Origins,
+    ratelimit::{Limit, Limits, RateLimits},
+    server::ChannelServer,
+    shutdown::shutdown_signal,
+    tls::TlsConfig,
+    utils::TopicGrant,
+};
+use clap::{parser::ValueSource, CommandFactory, Parser};
+use futures::future;
+use jsonwebtoken::Algorithm;
+use serde::Deserialize;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{watch, Notify},
 };
-use clap::Parser;
-use futures::StreamExt;
-use redis::{Client, RedisResult};
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::Mutex;
 use tower_http::services::ServeDir;
 use tracing::{error, info, warn};
-use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
-
-#[derive(Debug, Serialize, Deserialize)]
-struct TokenRequest {
-    channel: String,
-    id: Option<String>,
-}
-
-#[derive(Debug)]
-enum TokenError {
-    // ChannelNotFound,
-    GenerationFailed,
-}
-
-impl IntoResponse for TokenError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            // TokenError::ChannelNotFound => (StatusCode::NOT_FOUND, "Channel not found"),
-            TokenError::GenerationFailed => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to generate token"),
-        };
-
-        (status, message).into_response()
-    }
-}
-
-async fn generate_token(AxumState(state): AxumState<Arc<State>>, Json(req): Json<TokenRequest>) -> Result<impl IntoResponse, TokenError> {
-    // Check if channel exists
-    // let ctl = state.ctl.lock().await;
-    // let channels = ctl.channels.lock().await;
-    // if !channels.contains_key(&req.channel) {
-    //     error!("channel {} not found", req.channel);
-    //     return Err(TokenError::ChannelNotFound);
-    // }
-    let id_length = state.id_length as usize;
-    let id = req
-        .id
-        .filter(|id| !id.trim().is_empty())
-        .unwrap_or_else(|| nanoid::nanoid!(id_length).to_string());
-
-    match generate_jwt(id.clone(), req.channel.clone(), state.jwt_secret.clone(), state.jwt_expiration_secs).await {
-        Ok(token) => Ok(Json(serde_json::json!({
-            "id": id.clone(),
-            "channel": req.channel.clone(),
-            "token": token
-        }))),
-        Err(_) => Err(TokenError::GenerationFailed),
-    }
-}
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, Formatter},
+    reload, EnvFilter,
+};
 
-#[derive(Debug, Deserialize)]
-struct WebSocketParams {
-    #[serde(rename = "userToken")]
-    user_token: Option<String>,
+/// how often the config file is checked for changes
+const CONFIG_POLL: Duration = Duration::from_secs(5);
 
-    #[serde(rename = "vsn")]
-    version: String,
-}
+/// options of the config file applied by `ChannelServer::reload` and the log filter, the others need a restart
+const RELOADABLE: [&str; 9] = [
+    "log_level",
+    "max_frame_size",
+    "max_payload_size",
+    "max_joins",
+    "rate_limit_join",
+    "rate_limit_push",
+    "rate_limit_user",
+    "rate_limit_disconnect_after",
+    "read_only_topics",
+];
 
-async fn websocket_handler(
-    ws: WebSocketUpgrade, Query(params): Query<WebSocketParams>, AxumState(state): AxumState<Arc<State>>,
-) -> impl IntoResponse {
-    info!("version: {}", params.version);
-    ws.on_upgrade(move |socket| axum_on_connected(socket, state, params.user_token.clone()))
-}
+type LogFilter = reload::Handle<EnvFilter, Formatter>;
 
 // use clap to parse command line arguments
 #[derive(Debug, Deserialize, Parser)]
 #[command(name = "wd", about = "channel server")]
 struct Options {
+    /// TOML file of the options, by their long name with `_`, and of the `[[topics]]`; the command line and the environment win,
+    /// limits, policies and the log level are reloaded on SIGHUP or when the file changes
+    #[arg(long, env, default_value = None)]
+    config: Option<PathBuf>,
+
+    /// log filter, e.g. `info,channel::websocket=debug`; `RUST_LOG` or `info` if missing
+    #[arg(long, env, default_value = None)]
+    log_level: Option<String>,
+
     #[arg(long, env, default_value = "127.0.0.1")]
     host: Option<String>,
 
     #[arg(long, env, default_value = "2025")]
     port: Option<u16>,
 
+    /// listeners and their routes, repeatable, e.g. `0.0.0.0:2025?routes=socket,token,health` and
+    /// `unix:/run/channel/admin.sock?mode=660&routes=api,metrics`; `--host:--port` with every route if missing
+    #[arg(long, env)]
+    listen: Vec<ListenerConfig>,
+
     #[arg(long, env, default_value = None)]
     redis_url: Option<String>,
 
-    #[arg(long, env,default_value = "8")]
+    #[arg(long, env, default_value = "8")]
     id_length: u8,
 
     #[arg(long, env, default_value = None)]
@@ -105,41 +85,132 @@ struct Options {
     #[arg(long, env, default_value = "259200")]
     jwt_expiration_secs: i64,
 
+    /// agents get `token_expiring` this long before their token expires
+    #[arg(long, env, default_value = "60")]
+    token_refresh_window_secs: u64,
+
+    /// accepted token algorithms, e.g. `HS256,RS256,ES256`
+    #[arg(long, env, value_delimiter = ',', default_value = "HS256")]
+    jwt_algorithms: Vec<Algorithm>,
+
+    /// PEM public keys the RS/ES/EdDSA tokens are verified with, comma separated
+    #[arg(long, env, value_delimiter = ',')]
+    jwt_public_keys: Vec<PathBuf>,
+
+    /// a JWKS document, a file or an `http://` url, keys are chosen by `kid`
+    #[arg(long, env, default_value = None)]
+    jwks: Option<String>,
+
+    /// how often the JWKS is fetched again, 0 never
+    #[arg(long, env, default_value = "300")]
+    jwks_refresh_secs: u64,
+
+    /// HS256 secrets by `kid`, `{"active": "k2", "keys": {"k1": "...", "k2": "..."}}`, reloaded when the file changes
+    #[arg(long, env, default_value = None)]
+    jwt_keyring: Option<PathBuf>,
+
+    /// PEM private key `POST /token` signs with, the key ring or the secret if missing
+    #[arg(long, env, default_value = None)]
+    jwt_signing_key: Option<PathBuf>,
+
+    /// algorithm of the signing key
+    #[arg(long, env, default_value = "RS256")]
+    jwt_signing_algorithm: Algorithm,
+
+    /// `kid` of the signing key
+    #[arg(long, env, default_value = None)]
+    jwt_signing_kid: Option<String>,
+
+    /// required `iss` of the tokens, set in the tokens of `POST /token`
+    #[arg(long, env, default_value = None)]
+    jwt_issuer: Option<String>,
+
+    /// accepted `aud` of the tokens, comma separated, the first is set in the tokens of `POST /token`
+    #[arg(long, env, value_delimiter = ',')]
+    jwt_audience: Vec<String>,
+
+    /// seconds of clock skew allowed for `exp`, `nbf` and `iat`
+    #[arg(long, env, default_value = "60")]
+    jwt_leeway_secs: u64,
+
+    /// refuse tokens without a `jti`
+    #[arg(long, env)]
+    jwt_require_jti: bool,
+
+    /// refuse tokens living longer, from `iat` to `exp`; revocations are kept as long, forever if missing
+    #[arg(long, env, default_value = None)]
+    jwt_max_lifetime_secs: Option<u64>,
+
+    /// origins allowed to open websockets and call the HTTP routes, comma separated, e.g. `https://*.example.com`; any if missing
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+
+    /// topics clients can join but not push to, comma separated patterns, e.g. `news:*`
+    #[arg(long, env, value_delimiter = ',')]
+    read_only_topics: Vec<String>,
+
+    /// PEM certificate chain, served over TLS with `--tls-key`, reloaded when the file changes
+    #[arg(long, env, default_value = None, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`
+    #[arg(long, env, default_value = None, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CAs of client certificates, joins without a token use the certificate's common name as user id
+    #[arg(long, env, default_value = None, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// refuse TLS handshakes without a client certificate
+    #[arg(long, env, requires = "tls_client_ca")]
+    tls_client_cert_required: bool,
+
     #[arg(long, env, default_value = "assets")]
     static_path: Option<String>,
-}
 
-async fn keepalive(state: Arc<State>) -> RedisResult<()> {
-    let redis_client = state.redis_client.clone();
+    /// seconds to drain connections on SIGTERM/ctrl-c before exiting anyway
+    #[arg(long, env, default_value = "10")]
+    shutdown_timeout_secs: u64,
 
-    let redis_topic = "from:*:heartbeat".to_string();
-    let mut redis_pubsub = redis_client.get_async_pubsub().await?;
-    redis_pubsub.psubscribe(redis_topic.clone()).await?;
-    let mut redis_pubsub_stream = redis_pubsub.on_message();
+    /// bearer token of the HTTP API (`/api/...`), the API is disabled without it
+    #[arg(long, env, default_value = None)]
+    api_key: Option<String>,
 
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+    /// how long a long-poll GET waits for messages
+    #[arg(long, env, default_value = "10")]
+    longpoll_window_secs: u64,
 
-    loop {
-        tokio::select! {
-            _ = interval.tick() => {}
-            optional_message = redis_pubsub_stream.next() => {
-                if optional_message.is_none() {
-                    error!("KEEPALIVE / from redis: none");
-                    continue;
-                }
-                // payload JSON: {"conn_id": conn_id}
-                let payload = optional_message.unwrap().get_payload::<String>().unwrap();
-                let value_result: serde_json::Result<serde_json::Value> = serde_json::from_str(&payload);
-                if value_result.is_err() {
-                    error!("KEEPALIVE / from redis: parse error: {}", payload);
-                    continue;
-                }
-                if let Some(conn_id) = value_result.unwrap()["conn_id"].as_str() {
-                    info!("KEEPALIVE / heartbeat {:?}", conn_id);
-                }
-            }
-        }
-    }
+    /// long-poll sessions without any request for this long are closed
+    #[arg(long, env, default_value = "20")]
+    longpoll_idle_timeout_secs: u64,
+
+    /// bytes of a websocket message, the connection is closed with 1009 above
+    #[arg(long, env, default_value = "1048576")]
+    max_frame_size: usize,
+
+    /// bytes of a message payload, rejected above
+    #[arg(long, env, default_value = "262144")]
+    max_payload_size: usize,
+
+    /// joined topics per connection
+    #[arg(long, env, default_value = "100")]
+    max_joins: usize,
+
+    /// phx_join per connection, `burst/per_sec`, e.g. `5/1`; unlimited if missing
+    #[arg(long, env, default_value = None)]
+    rate_limit_join: Option<Limit>,
+
+    /// pushes per connection, topic and event, `burst/per_sec`; unlimited if missing
+    #[arg(long, env, default_value = None)]
+    rate_limit_push: Option<Limit>,
+
+    /// messages per user across connections, counted by each node, `burst/per_sec`; unlimited if missing
+    #[arg(long, env, default_value = None)]
+    rate_limit_user: Option<Limit>,
+
+    /// rate limited messages in a row before the connection is closed, 0 never
+    #[arg(long, env, default_value = "10")]
+    rate_limit_disconnect_after: u32,
 }
 
 #[tokio::main]
@@ -148,64 +219,258 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // 设置 tracing 使用 EnvFilter
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(env_filter)
         .with_span_events(FmtSpan::CLOSE)
-        .init();
+        .with_filter_reloading();
+    let log_filter = subscriber.reload_handle();
+    subscriber.init();
 
     let options = Options::parse(); // exit on error
+    let loaded = match &options.config {
+        Some(path) => match ConfigFile::load(path).await {
+            Ok(config_file) => parse_options(&config_file)
+                .map(|options| (options, Some(config_file)))
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) => Err(e.to_string()),
+        },
+        None => Ok((options, None)),
+    };
+    let (options, config_file) = match loaded.and_then(|(options, config_file)| set_log_level(&log_filter, &options).map(|_| (options, config_file)))
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{}", e.trim_end());
+            std::process::exit(2);
+        }
+    };
     if options.redis_url.is_none() {
         error!("redis_url is missing");
         return Ok(());
     }
-
-    let redis_url = options.redis_url.unwrap();
-    let redis_client = Client::open(redis_url.clone())?;
-    let channel_control = ChannelControl::new(Arc::new(redis_client.clone()));
-
-    let jwt_secret = options.jwt_secret.unwrap_or_else(|| {
-        let random_secret = random_string(8);
-        warn!("no secret proviced, generated: {}", random_secret);
-        random_secret
-    });
+    let reloadable = reloadable(&options, config_file.as_ref());
 
     info!("JWT default expiration: {} seconds / {} day(s)", options.jwt_expiration_secs, options.jwt_expiration_secs / 86400);
 
-    let state = Arc::new(State {
-        ctl: Mutex::new(channel_control),
-        redis_client,
-        id_length: options.id_length,
-        jwt_secret, // 从命令行、环境变量中获取，或者生成一个随机的
-        jwt_expiration_secs: options.jwt_expiration_secs, // default: 3 days
+    let mut builder = ChannelServer::builder()
+        .redis_url(options.redis_url.unwrap())
+        .id_length(options.id_length)
+        .jwt_expiration_secs(options.jwt_expiration_secs) // default: 3 days
+        .token_refresh_window(Duration::from_secs(options.token_refresh_window_secs))
+        .api_key(options.api_key)
+        .origins(Origins::new(options.allowed_origins))
+        .topic_policies(reloadable.topic_policies)
+        .jwt(JwtConfig {
+            algorithms: options.jwt_algorithms,
+            public_keys: options.jwt_public_keys,
+            jwks: options.jwks,
+            jwks_refresh: Duration::from_secs(options.jwks_refresh_secs),
+            keyring: options.jwt_keyring,
+            signing_key: options.jwt_signing_key,
+            signing_algorithm: options.jwt_signing_algorithm,
+            signing_kid: options.jwt_signing_kid,
+            issuer: options.jwt_issuer,
+            audience: options.jwt_audience,
+            leeway: options.jwt_leeway_secs,
+            require_jti: options.jwt_require_jti,
+            max_lifetime: options.jwt_max_lifetime_secs,
+        })
+        .message_limits(reloadable.message_limits)
+        .longpoll(Duration::from_secs(options.longpoll_window_secs), Duration::from_secs(options.longpoll_idle_timeout_secs));
+    if reloadable.rate_limits != RateLimits::default() {
+        info!("rate limits: {:?}", reloadable.rate_limits);
+    }
+    builder = builder.rate_limits(reloadable.rate_limits); // even without limits, to reload them
+    if let Some(jwt_secret) = options.jwt_secret {
+        builder = builder.jwt_secret(jwt_secret); // 从命令行、环境变量中获取，或者生成一个随机的
+    }
+    let server = Arc::new(builder.build().await?);
+    if let (Some(path), Some(config_file)) = (options.config, config_file) {
+        tokio::spawn(watch_config(path, config_file, server.clone(), log_filter));
+    }
+
+    let listeners = match options.listen.is_empty() {
+        true => vec![format!("{}:{}", options.host.unwrap(), options.port.unwrap()).parse::<ListenerConfig>()?],
+        false => options.listen,
+    };
+    let tls = match (options.tls_cert, options.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsConfig {
+            cert,
+            key,
+            client_ca: options.tls_client_ca,
+            client_cert_required: options.tls_client_cert_required,
+        }),
+        _ => None,
+    };
+
+    // drain once, then every listener stops accepting
+    let shutdown_started = Arc::new(Notify::new());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn({
+        let server = server.clone();
+        let shutdown_started = shutdown_started.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown_started.notify_one();
+            server.shutdown().await;
+            let _ = shutdown_tx.send(true);
+        }
     });
 
-    tokio::spawn(keepalive(state.clone()));
+    let static_path = options.static_path.unwrap();
+    let mut serves = vec![];
+    for listener in listeners.iter() {
+        let mut app = server.routes(&listener.routes);
+        if listener.routes.contains(&RouteSet::Socket) {
+            app = app.fallback_service(ServeDir::new(&static_path)); // Use fallback_service instead of nest_service for root path
+        }
+        let mut shutdown_rx = shutdown_rx.clone();
+        let shutdown = async move {
+            let _ = shutdown_rx.wait_for(|done| *done).await;
+        };
+        serves.push(listener.bind(app, tls.as_ref(), shutdown).await?);
+    }
+    let shutdown_timeout = Duration::from_secs(options.shutdown_timeout_secs);
+    tokio::select! {
+        result = future::try_join_all(serves) => { result.unwrap(); }
+        _ = async { shutdown_started.notified().await; tokio::time::sleep(shutdown_timeout).await } => {
+            warn!("shutdown deadline of {:?} exceeded, exiting", shutdown_timeout);
+        }
+    }
+    info!("bye");
 
-    // phoenix & admin are special
-    add_channel(&state.ctl, "phoenix".into()).await;
-    launch_channel_redis_listen_task(state.clone(), &state.ctl, "phoenix".into(), state.redis_client.clone()).await;
+    Ok(())
+}
 
-    add_channel(&state.ctl, "admin".into()).await;
-    launch_channel_redis_listen_task(state.clone(), &state.ctl, "admin".into(), state.redis_client.clone()).await;
+/// the options of the command line and the environment, the config file fills in the ones they don't set
+fn parse_options(config_file: &ConfigFile) -> Result<Options, String> {
+    let args = std::env::args_os().collect::<Vec<_>>();
+    let command = Options::command();
+    let matches = command.clone().try_get_matches_from(&args).map_err(|e| e.to_string())?;
 
-    // predefined channel
-    add_channel(&state.ctl, "system".into()).await;
-    launch_channel_redis_listen_task(state.clone(), &state.ctl, "system".into(), state.redis_client.clone()).await;
+    let mut file_args: Vec<OsString> = args[..1].to_vec();
+    for (key, value) in config_file.options.iter() {
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == key.as_str() && key != "config") else {
+            return Err(format!("unknown option `{}`", key));
+        };
+        if matches!(matches.value_source(key), Some(ValueSource::CommandLine | ValueSource::EnvVariable)) {
+            continue;
+        }
+        let flag = format!("--{}", arg.get_long().unwrap_or(key));
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) if !arg.get_action().takes_values() => file_args.push(flag.clone().into()),
+                toml::Value::Boolean(false) if !arg.get_action().takes_values() => {}
+                toml::Value::String(value) => file_args.push(format!("{}={}", flag, value).into()),
+                toml::Value::Integer(value) => file_args.push(format!("{}={}", flag, value).into()),
+                toml::Value::Float(value) => file_args.push(format!("{}={}", flag, value).into()),
+                _ => {
+                    return Err(format!(
+                        "`{}` takes {}",
+                        key,
+                        if arg.get_action().takes_values() {
+                            "a string or a number"
+                        } else {
+                            "true or false"
+                        }
+                    ))
+                }
+            }
+        }
+    }
+    file_args.extend(args.into_iter().skip(1));
+    Options::try_parse_from(file_args).map_err(|e| e.to_string())
+}
 
-    tokio::spawn(datetime_handler(state.clone(), "system".into()));
+/// limits, policies and rate limits of the options and the `[[topics]]` of the config file
+fn reloadable(options: &Options, config_file: Option<&ConfigFile>) -> Reloadable {
+    let config_file = config_file.cloned().unwrap_or_default();
+    let mut topic_policies = config_file.topic_policies();
+    topic_policies.extend(options.read_only_topics.iter().map(|topic| TopicGrant {
+        topic: topic.clone(),
+        read: true,
+        write: false,
+        events: vec![],
+    }));
+    let limits = Limits {
+        join: options.rate_limit_join,
+        push: options.rate_limit_push,
+        user: options.rate_limit_user,
+    };
+    Reloadable {
+        message_limits: MessageLimits {
+            max_frame_size: options.max_frame_size,
+            max_payload_size: options.max_payload_size,
+            event_payload_sizes: config_file.event_payload_sizes.clone(),
+            max_joins: options.max_joins,
+        },
+        topic_policies,
+        rate_limits: RateLimits {
+            topics: config_file.topic_rate_limits(&limits),
+            default: limits,
+            disconnect_after: options.rate_limit_disconnect_after,
+        },
+    }
+}
 
-    let host = options.host.unwrap();
-    let port = options.port.unwrap();
+fn set_log_level(log_filter: &LogFilter, options: &Options) -> Result<(), String> {
+    let env_filter = match &options.log_level {
+        Some(log_level) => EnvFilter::try_new(log_level).map_err(|e| format!("invalid log_level {}: {}", log_level, e))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    log_filter.reload(env_filter).map_err(|e| e.to_string())
+}
 
-    let app = Router::new()
-        .route("/websocket", get(websocket_handler))
-        .route("/token", post(generate_token))
-        .fallback_service(ServeDir::new(options.static_path.unwrap())) // Use fallback_service instead of nest_service for root path
-        .with_state(state.clone());
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, port)).await.unwrap();
+async fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    tokio::fs::metadata(path).await.and_then(|metadata| metadata.modified()).ok()
+}
 
-    info!("serving at {}:{} ...", host, port);
-    axum::serve(listener, app).await.unwrap();
+/// reload the config file on SIGHUP or when it changes, a broken file keeps the current settings
+async fn watch_config(path: PathBuf, mut current: ConfigFile, server: Arc<ChannelServer>, log_filter: LogFilter) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    let mut interval = tokio::time::interval(CONFIG_POLL);
+    let mut last_modified = modified(&path).await;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!("CONFIG / SIGHUP, reloading {}", path.display()),
+            _ = interval.tick() => {
+                if modified(&path).await == last_modified {
+                    continue;
+                }
+                info!("CONFIG / {} changed, reloading", path.display());
+            }
+        }
+        last_modified = modified(&path).await;
 
-    Ok(())
+        let reloaded = match ConfigFile::load(&path).await {
+            Ok(config_file) => parse_options(&config_file).map(|options| (options, config_file)),
+            Err(e) => Err(e.to_string()),
+        };
+        let (options, config_file) = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("CONFIG / keeping the current settings, {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let keys = current
+            .options
+            .keys()
+            .chain(config_file.options.keys())
+            .collect::<std::collections::BTreeSet<_>>();
+        for key in keys.into_iter().filter(|key| !RELOADABLE.contains(&key.as_str())) {
+            if current.options.get(key) != config_file.options.get(key) {
+                warn!("CONFIG / {} changed, restart to apply it", key);
+            }
+        }
+        if let Err(e) = set_log_level(&log_filter, &options) {
+            error!("CONFIG / keeping the log level: {}", e);
+        }
+        server.reload(reloadable(&options, Some(&config_file)));
+        current = config_file;
+    }
 }
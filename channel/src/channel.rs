@@ -1,16 +1,19 @@
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use itertools::Itertools;
 use redis::{AsyncCommands, RedisResult};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, VecDeque},
     error::Error,
     fmt::{self, Display},
+    net::SocketAddr,
     sync::{
         atomic::{AtomicU32, AtomicU64, Ordering},
         Arc,
     },
+    time::Instant,
 };
 use tokio::{
     sync::{
@@ -21,11 +24,89 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
+use crate::metrics::METRICS;
+use crate::tls::ClientIdentity;
+use crate::utils::Claims;
 use crate::websocket::{Response, ServerMessage, ServerPayload, State};
 
 #[derive(Clone, Debug, Serialize)]
 pub enum ChannelMessage {
     Reply(ServerMessage),
+
+    /// broadcast to every agent of the channel except `exclude`, like Phoenix's `broadcast_from`
+    /// agents receive it as a `Reply`
+    BroadcastFrom {
+        reply: ServerMessage,
+        exclude: String,
+    },
+
+    /// a message from redis, relayed as is so that the delay until the client gets it can be measured
+    Redis {
+        reply: ServerMessage,
+        seq: u64, // in the channel's `History`
+        #[serde(skip)]
+        received_at: Instant,
+    },
+
+    /// close the connection, websockets send a close frame with `code`
+    Close {
+        code: u16,
+        reason: String,
+    },
+}
+
+/// websocket close code when the server shuts down
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+/// websocket close code when a frame exceeds `MessageLimits::max_frame_size`
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+/// websocket close code when a client keeps exceeding the rate limits
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+impl ChannelMessage {
+    /// the message sent to the client, `None` for `Close`
+    pub fn into_reply(self) -> Option<ServerMessage> {
+        match self {
+            ChannelMessage::Reply(reply) | ChannelMessage::BroadcastFrom { reply, .. } | ChannelMessage::Redis { reply, .. } => Some(reply),
+            ChannelMessage::Close { .. } => None,
+        }
+    }
+
+    pub fn reply_mut(&mut self) -> Option<&mut ServerMessage> {
+        match self {
+            ChannelMessage::Reply(reply) | ChannelMessage::BroadcastFrom { reply, .. } | ChannelMessage::Redis { reply, .. } => Some(reply),
+            ChannelMessage::Close { .. } => None,
+        }
+    }
+
+    pub fn received_at(&self) -> Option<Instant> {
+        match self {
+            ChannelMessage::Redis { received_at, .. } => Some(*received_at),
+            _ => None,
+        }
+    }
+
+    /// the sequence number of a message from redis
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            ChannelMessage::Redis { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+
+    /// the message for an agent, its join_ref set, `Redis` keeps its timestamp
+    pub fn with_join_ref(self, join_ref: Option<String>) -> ChannelMessage {
+        match self {
+            ChannelMessage::Reply(mut reply) | ChannelMessage::BroadcastFrom { mut reply, .. } => {
+                reply.join_ref = join_ref;
+                ChannelMessage::Reply(reply)
+            }
+            ChannelMessage::Redis { mut reply, seq, received_at } => {
+                reply.join_ref = join_ref;
+                ChannelMessage::Redis { reply, seq, received_at }
+            }
+            close @ ChannelMessage::Close { .. } => close,
+        }
+    }
 }
 
 impl Display for ChannelMessage {
@@ -34,6 +115,15 @@ impl Display for ChannelMessage {
             ChannelMessage::Reply(reply) => {
                 write!(formatter, "<{}>", reply)
             }
+            ChannelMessage::BroadcastFrom { reply, exclude } => {
+                write!(formatter, "<{} exclude={}>", reply, exclude)
+            }
+            ChannelMessage::Redis { reply, .. } => {
+                write!(formatter, "<{} from redis>", reply)
+            }
+            ChannelMessage::Close { code, reason } => {
+                write!(formatter, "<Close code={} reason={}>", code, reason)
+            }
         }
     }
 }
@@ -45,6 +135,8 @@ pub struct Channel {
     pub agents: Mutex<Vec<String>>,
     pub count: AtomicU32,
     pub redis_listen_task: Option<JoinHandle<RedisResult<()>>>,
+    pub history: Arc<History>, // recent messages from redis, replayed on SSE resume
+    metrics_label: String,     // the topic pattern the agents are counted by
 }
 
 /// manages all channels
@@ -54,6 +146,19 @@ pub struct ChannelControl {
     pub agents: Mutex<HashMap<String, Agent>>,                           // agent_id -> JoinHandle
     agent_tx: Mutex<HashMap<String, broadcast::Sender<ChannelMessage>>>, // agent_id -> Sender, TODO: replace with `agents`
     conn_tx: Mutex<HashMap<String, broadcast::Sender<ChannelMessage>>>,  // conn_id -> Sender
+    conns: Mutex<HashMap<String, ConnInfo>>,                             // conn_id -> ConnInfo
+}
+
+/// a client connection, as listed by the admin API
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    pub conn_id: String,
+    pub transport: String, // websocket, longpoll
+    pub remote_addr: Option<SocketAddr>,
+    pub connected_at: DateTime<Utc>,
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    pub client_identity: Option<ClientIdentity>, // the client certificate of a mutual TLS connection
+    pub external_id: Option<String>,             // of the first token an agent joined with
 }
 
 #[derive(Debug)]
@@ -61,9 +166,26 @@ pub struct Agent {
     pub channel: String,
     pub id: String,
     pub external_id: String,
+    pub meta: serde_json::Value, // extra presence metas, besides `phx_ref`
+    pub claims: Option<Claims>,  // of the token the agent joined with
     relay_task: JoinHandle<()>,
 }
 
+impl Agent {
+    pub fn presence_meta(&self) -> serde_json::Value {
+        presence_meta(&self.id, &self.meta)
+    }
+}
+
+/// presence meta of an agent, `{"phx_ref": agent_id, ...meta}`
+pub fn presence_meta(agent_id: &str, meta: &serde_json::Value) -> serde_json::Value {
+    let mut presence_meta = json!({ "phx_ref": agent_id });
+    if let (Some(presence_meta), Some(meta)) = (presence_meta.as_object_mut(), meta.as_object()) {
+        presence_meta.extend(meta.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    presence_meta
+}
+
 impl Display for Agent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<Agent: id={} external_id={} channel={}, task={:?}>", self.id, self.external_id, self.channel, self.relay_task)
@@ -77,6 +199,9 @@ pub enum ChannelError {
     MessageSendError,
     AgentNotInitiated,
     BadToken,
+    JoinRejected,
+    TokenRevoked,
+    Unauthorized,
 }
 
 impl Error for ChannelError {}
@@ -89,20 +214,55 @@ impl fmt::Display for ChannelError {
             ChannelError::AgentNotInitiated => write!(formatter, "<AgentNotInitiated>"),
             ChannelError::MessageSendError => write!(formatter, "<MessageSendError: failed to send a message to the channel>"),
             ChannelError::BadToken => write!(formatter, "<InvalidPayload: invalid payload format>"),
+            ChannelError::JoinRejected => write!(formatter, "<JoinRejected: rejected by the channel handler>"),
+            ChannelError::TokenRevoked => write!(formatter, "<TokenRevoked: the token or its user is revoked>"),
+            ChannelError::Unauthorized => write!(formatter, "<Unauthorized: the token doesn't grant the topic>"),
         }
     }
 }
 
+/// how many messages from redis each channel keeps for `Last-Event-ID` resume
+pub const HISTORY_CAPACITY: usize = 100;
+
+/// the recent messages from redis of a channel, numbered by a sequence that keeps growing while the channel exists,
+/// across restarts of its redis listener
+#[derive(Default)]
+pub struct History {
+    messages: std::sync::Mutex<VecDeque<(u64, ServerMessage)>>,
+    seq: AtomicU64,
+}
+
+impl History {
+    /// number the message, its `event_ref` too, and keep it, the oldest message is dropped beyond `HISTORY_CAPACITY`
+    pub fn push(&self, mut message: ServerMessage) -> (u64, ServerMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        message.event_ref = seq.to_string();
+        if messages.len() == HISTORY_CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back((seq, message.clone()));
+        (seq, message)
+    }
+
+    /// the kept messages with a sequence number greater than `last_seq`
+    pub fn since(&self, last_seq: u64) -> Vec<(u64, ServerMessage)> {
+        self.messages.lock().unwrap().iter().filter(|(seq, _)| *seq > last_seq).cloned().collect()
+    }
+}
+
 impl Channel {
     // capacity is the maximum number of messages that can be stored in the channel
     pub fn new(name: String, capacity: Option<usize>) -> Channel {
         let (tx, _rx) = broadcast::channel(capacity.unwrap_or(100));
         Channel {
-            name,
             tx,
             agents: Mutex::new(vec![]),
             count: AtomicU32::new(0),
             redis_listen_task: None,
+            history: Arc::new(History::default()),
+            metrics_label: METRICS.topic_label(&name),
+            name,
         }
     }
 
@@ -113,6 +273,7 @@ impl Channel {
         if !agents.contains(&agent_id) {
             agents.push(agent_id.clone());
             self.count.fetch_add(1, Ordering::SeqCst);
+            METRICS.agent_joined(&self.metrics_label);
             info!("C / {}, total: {:?}, agent added {}", self.name, self.count, agent_id);
         } else {
             info!("C / {}, total: {:?}, agent {} exists", self.name, self.count, agent_id);
@@ -127,12 +288,14 @@ impl Channel {
             // - 删除 index 位置的，用最后一个顶替这个位置
             let agent = agents.swap_remove(pos);
             self.count.fetch_sub(1, Ordering::SeqCst);
+            METRICS.agents_left(&self.metrics_label, 1);
             info!("C / {}, total: {:?}, agent removed {}", self.name, self.count, agent);
         }
     }
 
     /// broadcast messages to the channel
     /// it returns the number of agents who received the message
+    #[allow(clippy::result_large_err)]
     pub fn send(&self, data: ChannelMessage) -> Result<usize, SendError<ChannelMessage>> {
         self.tx.send(data)
     }
@@ -141,9 +304,18 @@ impl Channel {
         self.count.load(Ordering::SeqCst) == 0
     }
 
-    pub async fn agents(&self) -> tokio::sync::MutexGuard<Vec<String>> {
+    pub async fn agents(&self) -> tokio::sync::MutexGuard<'_, Vec<String>> {
         self.agents.lock().await
     }
+
+    /// status of the redis listener: none, running or stopped
+    pub fn listener_status(&self) -> &'static str {
+        match &self.redis_listen_task {
+            None => "none",
+            Some(task) if task.is_finished() => "stopped",
+            Some(_) => "running",
+        }
+    }
 }
 
 impl Default for ChannelControl {
@@ -161,9 +333,68 @@ impl ChannelControl {
             agent_tx: Mutex::new(HashMap::new()),
             agents: Mutex::new(HashMap::new()),
             conn_tx: Mutex::new(HashMap::new()),
+            conns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// add a client connection, `conn_add_tx` and the details listed by the admin API
+    pub async fn conn_add(&self, conn_id: String, transport: &str, remote_addr: Option<SocketAddr>) {
+        self.conn_add_tx(conn_id.clone()).await;
+        let conn_info = ConnInfo {
+            conn_id: conn_id.clone(),
+            transport: transport.to_string(),
+            remote_addr,
+            connected_at: Utc::now(),
+            last_heartbeat: None,
+            client_identity: None,
+            external_id: None,
+        };
+        if self.conns.lock().await.insert(conn_id, conn_info).is_none() {
+            METRICS.connections.inc();
+        }
+    }
+
+    pub async fn conn_heartbeat(&self, conn_id: &str) {
+        if let Some(conn_info) = self.conns.lock().await.get_mut(conn_id) {
+            conn_info.last_heartbeat = Some(Utc::now());
         }
     }
 
+    /// the client certificate of the connection, joins without a token use it
+    pub async fn conn_set_identity(&self, conn_id: &str, client_identity: ClientIdentity) {
+        if let Some(conn_info) = self.conns.lock().await.get_mut(conn_id) {
+            conn_info.client_identity = Some(client_identity);
+        }
+    }
+
+    pub async fn conn_identity(&self, conn_id: &str) -> Option<ClientIdentity> {
+        self.conns
+            .lock()
+            .await
+            .get(conn_id)
+            .and_then(|conn_info| conn_info.client_identity.clone())
+    }
+
+    /// the user of the connection's agents, known once one joined
+    pub async fn conn_external_id(&self, conn_id: &str) -> Option<String> {
+        self.conns.lock().await.get(conn_id).and_then(|conn_info| conn_info.external_id.clone())
+    }
+
+    /// connections added by `conn_add`, sorted by connect time
+    pub async fn conn_list(&self) -> Vec<ConnInfo> {
+        let mut conns = self.conns.lock().await.values().cloned().collect::<Vec<_>>();
+        conns.sort_by_key(|conn_info| conn_info.connected_at);
+        conns
+    }
+
+    pub async fn conn_ids(&self) -> Vec<String> {
+        self.conn_tx.lock().await.keys().cloned().collect()
+    }
+
+    pub async fn conn_exists(&self, conn_id: &str) -> bool {
+        self.conn_tx.lock().await.contains_key(conn_id)
+    }
+
     pub async fn conn_add_tx(&self, conn_id: String) {
         let mut conn_tx = self.conn_tx.lock().await;
         match conn_tx.entry(conn_id.clone()) {
@@ -200,14 +431,14 @@ impl ChannelControl {
             .lock()
             .await
             .iter()
-            .filter(|(_, agent)| agent.id.starts_with(&conn_id))
+            .filter(|(_, agent)| agent.id.starts_with(&conn_id) && agent.channel == channel_name)
             .into_group_map_by(|(_, agent)| &agent.external_id)
             .into_iter()
             .map(|(external_id, group)| {
                 (external_id.clone(), {
                     json!({
                         "metas": group.into_iter()
-                            .map(|(id, _)| json!({ "phx_ref": id }))
+                            .map(|(_, agent)| agent.presence_meta())
                             .collect::<Vec<_>>()
                     })
                 })
@@ -231,6 +462,7 @@ impl ChannelControl {
         let message = serde_json::to_string(&diff).unwrap();
         let publish_result: RedisResult<String> = redis_conn.publish(redis_topic.clone(), message.clone()).await;
         if let Err(e) = publish_result {
+            METRICS.redis_publish_failures.inc();
             error!("CONN_CLEANUP / fail to publish to redis: {}", e)
         } else {
             info!("CONN_CLEANUP / sent");
@@ -246,6 +478,9 @@ impl ChannelControl {
         debug!("CONN / agent_tx cleared, conn_id: {}, {} {:?}", conn_id, agent_tx.len(), agent_tx.keys().collect::<Vec<&String>>());
 
         self.conn_tx.lock().await.remove_entry(&conn_id);
+        if self.conns.lock().await.remove(&conn_id).is_some() {
+            METRICS.connections.dec();
+        }
         debug!("CONN / conn_tx cleared, {}", conn_id);
 
         for (name, channel) in self.channels.lock().await.iter() {
@@ -303,6 +538,7 @@ impl ChannelControl {
         let message = serde_json::to_string(&meta).unwrap();
         let result: RedisResult<String> = redis_conn_result.unwrap().publish(redis_topic, message.clone()).await;
         if result.is_err() {
+            METRICS.redis_publish_failures.inc();
             error!("ADMIN_PUB / fail to publish to redis");
             return;
         }
@@ -330,6 +566,7 @@ impl ChannelControl {
                     info!("CH_RM / channel {} redis listen task aborted", channel_name);
                 }
 
+                METRICS.agents_left(&channel.metrics_label, channel.agents.lock().await.len());
                 entry.remove();
                 info!("CH_RM / removed from channels, {}", channel_name);
             }
@@ -349,6 +586,13 @@ impl ChannelControl {
     /// join agent to a channel
     pub async fn channel_join(
         &self, channel_name: &str, agent_id: String, external_id: String,
+    ) -> Result<broadcast::Sender<ChannelMessage>, ChannelError> {
+        self.channel_join_with_meta(channel_name, agent_id, external_id, json!({})).await
+    }
+
+    /// join agent to a channel, `meta` is added to the agent's presence metas
+    pub async fn channel_join_with_meta(
+        &self, channel_name: &str, agent_id: String, external_id: String, meta: serde_json::Value,
     ) -> Result<broadcast::Sender<ChannelMessage>, ChannelError> {
         let channels = self.channels.lock().await;
 
@@ -359,12 +603,26 @@ impl ChannelControl {
         let agent_tx = self.agent_tx.lock().await.get(&agent_id).ok_or(ChannelError::AgentNotInitiated)?.clone();
 
         // 订阅 channel 并将消息转发给 agent
+        let relay_agent_id = agent_id.clone();
         let relay_task = tokio::spawn(async move {
-            while let Ok(channel_message) = channel_rx.recv().await {
-                match &channel_message {
-                    ChannelMessage::Reply(_reply_message) => {
+            loop {
+                let channel_message = match channel_rx.recv().await {
+                    Ok(channel_message) => channel_message,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("AGENT / {} lagged, {} messages dropped", relay_agent_id, n);
+                        METRICS.lagged("agent", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                match channel_message {
+                    ChannelMessage::Reply(_) | ChannelMessage::Redis { .. } | ChannelMessage::Close { .. } => {
                         let _ = agent_tx.send(channel_message);
                     }
+                    ChannelMessage::BroadcastFrom { ref exclude, .. } if *exclude == relay_agent_id => {}
+                    ChannelMessage::BroadcastFrom { reply, .. } => {
+                        let _ = agent_tx.send(ChannelMessage::Reply(reply));
+                    }
                 }
             }
         });
@@ -378,6 +636,8 @@ impl ChannelControl {
                     id: agent_id.clone(),
                     external_id: external_id.clone(),
                     channel: channel_name.to_string().clone(),
+                    meta,
+                    claims: None,
                     relay_task,
                 });
             }
@@ -410,6 +670,13 @@ impl ChannelControl {
     }
 
     pub async fn channel_broadcast_json(&self, channel_name: &str, event_name: &str, value: serde_json::Value) -> Result<usize, ChannelError> {
+        self.channel_broadcast_json_from(channel_name, event_name, value, None).await
+    }
+
+    /// broadcast a JSON payload, skipping the agent `exclude` if there's one
+    pub async fn channel_broadcast_json_from(
+        &self, channel_name: &str, event_name: &str, value: serde_json::Value, exclude: Option<String>,
+    ) -> Result<usize, ChannelError> {
         let message = ServerMessage {
             join_ref: None,
             event_ref: "0".into(),
@@ -417,7 +684,11 @@ impl ChannelControl {
             event: event_name.to_string(),
             payload: ServerPayload::ServerJsonValue(value),
         };
-        self.channel_broadcast(channel_name.to_string(), ChannelMessage::Reply(message)).await
+        let message = match exclude {
+            Some(exclude) => ChannelMessage::BroadcastFrom { reply: message, exclude },
+            None => ChannelMessage::Reply(message),
+        };
+        self.channel_broadcast(channel_name.to_string(), message).await
     }
 
     /// broadcast message to the channel
@@ -497,10 +768,66 @@ impl ChannelControl {
         external_id
     }
 
+    /// remember the claims of the token the agent joined with, for revocations
+    /// the claims of the agent's token, the first ones of a connection make its `external_id`
+    pub async fn agent_set_claims(&self, agent_id: &str, claims: Claims) {
+        let conn_id = agent_id.split(':').next().unwrap_or_default();
+        if let Some(conn_info) = self.conns.lock().await.get_mut(conn_id) {
+            conn_info.external_id.get_or_insert_with(|| claims.id.clone());
+        }
+        if let Some(agent) = self.agents.lock().await.get_mut(agent_id) {
+            agent.claims = Some(claims);
+        }
+    }
+
+    /// send to the agent only, e.g. a `Close` ending an SSE stream
+    pub async fn agent_send(&self, agent_id: &str, message: ChannelMessage) {
+        if let Some(agent_tx) = self.agent_tx.lock().await.get(agent_id) {
+            let _ = agent_tx.send(message);
+        }
+    }
+
     /// list all agents
     pub async fn agent_list(&self) -> Vec<String> {
         self.agent_tx.lock().await.keys().cloned().collect()
     }
+
+    /// tell the client that the agent's channel is closed with `phx_close`, the join ref is the last part of the agent id
+    pub async fn agent_send_close(&self, agent_id: &str, topic: &str) {
+        self.agent_send_close_with(agent_id, topic, json!({})).await
+    }
+
+    /// `phx_close` with a payload, e.g. `{"reason": "token_expired"}`
+    pub async fn agent_send_close_with(&self, agent_id: &str, topic: &str, payload: serde_json::Value) {
+        let (Some(conn_id), Some(join_ref)) = (agent_id.split(':').next(), agent_id.rsplit(':').next()) else {
+            return;
+        };
+        let message = ServerMessage {
+            join_ref: Some(join_ref.to_string()),
+            event_ref: join_ref.to_string(),
+            topic: topic.to_string(),
+            event: "phx_close".to_string(),
+            payload: ServerPayload::ServerJsonValue(payload),
+        };
+        if let Err(e) = self.conn_send(conn_id.to_string(), ChannelMessage::Reply(message)).await {
+            debug!("AGENT / {}, fail to send phx_close: {}", agent_id, e);
+        }
+    }
+
+    /// presence of a channel, `{external_id: {"metas": [...]}}`
+    pub async fn channel_presence(&self, channel_name: &str) -> serde_json::Value {
+        let presence = self
+            .agents
+            .lock()
+            .await
+            .values()
+            .filter(|agent| agent.channel == channel_name)
+            .into_group_map_by(|agent| agent.external_id.clone())
+            .into_iter()
+            .map(|(external_id, group)| (external_id, json!({"metas": group.into_iter().map(Agent::presence_meta).collect::<Vec<_>>()})))
+            .collect::<HashMap<_, _>>();
+        json!(presence)
+    }
 }
 
 /// 从 Redis 反序列化的, 之后转发到 websocket
@@ -525,9 +852,9 @@ pub enum ResponseFromRedis {
     Message { message: String },
 }
 
-impl Into<Response> for ResponseFromRedis {
-    fn into(self) -> Response {
-        match self {
+impl From<ResponseFromRedis> for Response {
+    fn from(value: ResponseFromRedis) -> Self {
+        match value {
             ResponseFromRedis::Empty {} => Response::Empty {},
             ResponseFromRedis::Join { id } => Response::Join { id },
             ResponseFromRedis::Heartbeat {} => Response::Heartbeat {},
@@ -559,47 +886,12 @@ impl ChannelEventFromRedis {
 
 /// 从redis 监听消息, per channel 的任务
 pub async fn listen_to_redis(
-    state: Arc<State>, tx: broadcast::Sender<ChannelMessage>, redis_client: redis::Client, channel_name: String,
+    state: Arc<State>, tx: broadcast::Sender<ChannelMessage>, history: Arc<History>, redis_client: redis::Client, channel_name: String,
 ) -> RedisResult<()> {
     let redis_topic = format!("to:{}:*", channel_name);
     let mut redis_pubsub = redis_client.get_async_pubsub().await?;
     redis_pubsub.psubscribe(redis_topic.clone()).await?;
     let mut redis_pubsub_stream = redis_pubsub.on_message();
-    // let mut counter = 0; // TODO: counter 有问题, 在这里完全没有意义
-
-    // 使用 Arc<AtomicU64> 来安全地共享计数器
-    let counter = Arc::new(AtomicU64::new(0));
-    info!("LISTENER / subscribed to redis, channel: {}", redis_topic);
-
-    // 克隆一个计数器的引用用于统计线程
-    // let counter_for_stats = counter.clone();
-
-    // 启动统计任务
-    // let stat_channel_name = channel_name.clone();
-
-    // once channel to notify the stats thread to stop
-    // let exit_stat = Arc::new(AtomicBool::new(false));
-    // let stat_should_exit = exit_stat.clone();
-
-    // let stat_handler = tokio::spawn(async move {
-    //     let mut interval = time::interval(Duration::from_secs(1));
-    //     let mut last_count = 0u64;
-    //
-    //     loop {
-    //         if stat_should_exit.load(Ordering::Relaxed) {
-    //             info!("LISTENER / stats thread exit");
-    //             break;
-    //         }
-    //
-    //         interval.tick().await;
-    //         let current_count = counter_for_stats.load(Ordering::Relaxed);
-    //         let delta = current_count.saturating_sub(last_count);
-    //         if delta > 10 {
-    //             info!("LISTENER / {} Stats - Total messages: {}, Messages/sec: {}", stat_channel_name, current_count, delta);
-    //         }
-    //         last_count = current_count;
-    //     }
-    // });
 
     info!("LISTENER / subscribed to redis, channel: {}", redis_topic);
     loop {
@@ -610,6 +902,7 @@ pub async fn listen_to_redis(
         }
 
         let stream_message = optional_message.unwrap();
+        let received_at = Instant::now();
         let ev = match ChannelEventFromRedis::parse(stream_message.get_channel_name()) {
             Ok(ev) => ev,
             Err(err) => {
@@ -636,12 +929,17 @@ pub async fn listen_to_redis(
         // 检查是否有这个 channel
         let reply_message = ServerMessage {
             join_ref: None,
-            event_ref: counter.load(Ordering::Relaxed).to_string(),
+            event_ref: String::new(), // the sequence number, set by the history
             topic: ev.channel.to_string(),
             event: ev.event.to_string(),
             payload: ServerPayload::ServerJsonValue(value),
         };
-        match tx.send(ChannelMessage::Reply(reply_message.clone())) {
+        let (seq, reply_message) = history.push(reply_message);
+        match tx.send(ChannelMessage::Redis {
+            reply: reply_message,
+            seq,
+            received_at,
+        }) {
             Ok(_count) => {
                 // debug!("LISTENER / published, channel: {}, event: {}, receiver count {}", ev.channel, ev.event, count);
             }
@@ -657,10 +955,6 @@ pub async fn listen_to_redis(
                 break; // 选择退出当前线程，但是需要注意的是如果有新的agent 加入，需要重启这个线程
             }
         }
-
-        // 原子操作增加计数器
-        counter.fetch_add(1, Ordering::Relaxed);
-        // debug!("LISTENER / publish message from redis, counter: {}", counter);
     }
 
     // exit_stat.store(true, Ordering::Relaxed);
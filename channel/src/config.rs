@@ -0,0 +1,210 @@
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+};
+
+use crate::inbound::MessageLimits;
+use crate::ratelimit::{Limit, Limits, RateLimits};
+use crate::utils::TopicGrant;
+
+/// the settings `ChannelServer::reload` swaps without dropping connections, the others need a restart
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reloadable {
+    pub message_limits: MessageLimits,
+    pub topic_policies: Vec<TopicGrant>,
+    pub rate_limits: RateLimits,
+}
+
+/// `[[topics]]` of the config file, a policy and rate limits by topic pattern, the first match applies
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicConfig {
+    pub topic: String,
+    #[serde(default = "allowed")]
+    pub read: bool, // joins
+    #[serde(default = "allowed")]
+    pub write: bool, // pushes
+    #[serde(default)]
+    pub events: Vec<String>, // the events that can be pushed, any if empty
+    #[serde(default, deserialize_with = "limit")]
+    pub rate_limit_join: Option<Limit>, // `burst/per_sec`, the default limit if missing
+    #[serde(default, deserialize_with = "limit")]
+    pub rate_limit_push: Option<Limit>,
+    #[serde(default, deserialize_with = "limit")]
+    pub rate_limit_user: Option<Limit>,
+}
+
+fn allowed() -> bool {
+    true
+}
+
+fn limit<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Limit>, D::Error> {
+    String::deserialize(deserializer)?.parse().map(Some).map_err(de::Error::custom)
+}
+
+impl TopicConfig {
+    fn has_rate_limits(&self) -> bool {
+        self.rate_limit_join.is_some() || self.rate_limit_push.is_some() || self.rate_limit_user.is_some()
+    }
+}
+
+/// a TOML config file: the command line options by their long name with `_`, and the settings of the topics
+///
+/// ```toml
+/// redis_url = "redis://127.0.0.1:6379"
+/// listen = ["0.0.0.0:2025?routes=socket,token,health", "unix:/run/channel/admin.sock?mode=660&routes=api,metrics"]
+/// log_level = "info"
+/// rate_limit_push = "20/5"
+///
+/// [event_payload_sizes]
+/// upload = 1048576
+///
+/// [[topics]]
+/// topic = "news:*"
+/// write = false
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub topics: Vec<TopicConfig>,
+    #[serde(default)]
+    pub event_payload_sizes: HashMap<String, usize>, // `max_payload_size` by event
+    #[serde(flatten)]
+    pub options: toml::Table, // validated by the command line parser
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid(PathBuf, String),
+}
+
+impl Error for ConfigError {}
+
+impl Display for ConfigError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(formatter, "<Io: {}, {}>", path.display(), e),
+            ConfigError::Parse(path, e) => write!(formatter, "<Parse: {}, {}>", path.display(), e.trim_end()),
+            ConfigError::Invalid(path, e) => write!(formatter, "<Invalid: {}, {}>", path.display(), e),
+        }
+    }
+}
+
+impl ConfigFile {
+    pub async fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        ConfigFile::parse(path, &text)
+    }
+
+    /// parse and check what the command line parser doesn't, the path is for the errors
+    pub fn parse(path: &Path, text: &str) -> Result<Self, ConfigError> {
+        let config: ConfigFile = toml::from_str(text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e.to_string()))?;
+        let invalid = |e: String| ConfigError::Invalid(path.to_path_buf(), e);
+        for (i, topic) in config.topics.iter().enumerate() {
+            if topic.topic.is_empty() {
+                return Err(invalid(format!("topics[{}]: empty topic", i)));
+            }
+            if let Some(first) = config.topics[..i].iter().position(|other| other.topic == topic.topic) {
+                return Err(invalid(format!("topics[{}]: {} is already topics[{}], it would never apply", i, topic.topic, first)));
+            }
+        }
+        if let Some((event, _)) = config.event_payload_sizes.iter().find(|(_, size)| **size == 0) {
+            return Err(invalid(format!("event_payload_sizes.{}: 0 bytes", event)));
+        }
+        Ok(config)
+    }
+
+    /// the policies of the topics, in the order of the file
+    pub fn topic_policies(&self) -> Vec<TopicGrant> {
+        self.topics
+            .iter()
+            .map(|topic| TopicGrant {
+                topic: topic.topic.clone(),
+                read: topic.read,
+                write: topic.write,
+                events: topic.events.clone(),
+            })
+            .collect()
+    }
+
+    /// the rate limits of the topics that have some, the default limits fill in the others
+    pub fn topic_rate_limits(&self, default: &Limits) -> Vec<(String, Limits)> {
+        self.topics
+            .iter()
+            .filter(|topic| topic.has_rate_limits())
+            .map(|topic| {
+                let limits = Limits {
+                    join: topic.rate_limit_join.or(default.join),
+                    push: topic.rate_limit_push.or(default.push),
+                    user: topic.rate_limit_user.or(default.user),
+                };
+                (topic.topic.clone(), limits)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file() {
+        let path = Path::new("channel.toml");
+        let text = r#"
+            port = 2026
+            read_only_topics = ["alerts"]
+            rate_limit_push = "20/5"
+
+            [event_payload_sizes]
+            upload = 65536
+
+            [[topics]]
+            topic = "news:*"
+            write = false
+
+            [[topics]]
+            topic = "room:*"
+            events = ["shout"]
+            rate_limit_push = "5/1"
+        "#;
+        let config = ConfigFile::parse(path, text).unwrap();
+        assert_eq!(config.options["port"].as_integer(), Some(2026));
+        assert!(!config.options.contains_key("topics"));
+        assert_eq!(config.event_payload_sizes["upload"], 65536);
+
+        let policies = config.topic_policies();
+        assert_eq!(policies.len(), 2);
+        assert!(policies[0].read && !policies[0].write);
+        assert!(policies[1].can_push("shout") && !policies[1].can_push("whisper"));
+
+        let default = Limits {
+            join: Some("10/1".parse().unwrap()),
+            push: Some("20/5".parse().unwrap()),
+            user: None,
+        };
+        let limits = config.topic_rate_limits(&default);
+        assert_eq!(limits.len(), 1, "news:* has no limits of its own");
+        assert_eq!(limits[0].0, "room:*");
+        assert_eq!((limits[0].1.join, limits[0].1.push), (default.join, Some(Limit { burst: 5, per_sec: 1.0 })));
+
+        for (text, error) in [
+            ("port = ", "<Parse: channel.toml, TOML parse error at line 1, column 8"),
+            ("[[topics]]\ntopic = \"a\"\nwrit = false", "unknown field `writ`"),
+            ("[[topics]]\ntopic = \"a\"\nrate_limit_join = \"fast\"", "<LimitParseError: fast, expected burst/per_sec, e.g. 20/5>"),
+            ("[[topics]]\ntopic = \"\"", "<Invalid: channel.toml, topics[0]: empty topic>"),
+            ("[[topics]]\ntopic = \"a\"\n[[topics]]\ntopic = \"a\"", "topics[1]: a is already topics[0], it would never apply"),
+            ("[event_payload_sizes]\nupload = 0", "event_payload_sizes.upload: 0 bytes"),
+        ] {
+            let e = ConfigFile::parse(path, text).unwrap_err().to_string();
+            assert!(e.contains(error), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use redis::aio::MultiplexedConnection;
+use serde_json::json;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+
+use crate::channel::{ChannelMessage, CLOSE_POLICY_VIOLATION};
+use crate::revocation::is_revoked;
+use crate::websocket::{close_agent, ServerMessage, ServerPayload, State};
+
+/// how often the token expiries of the agents are checked
+const EXPIRY_TICK: Duration = Duration::from_secs(1);
+
+fn now() -> usize {
+    chrono::Utc::now().timestamp() as usize
+}
+
+/// agents whose token is about to expire get `token_expiring`, agents whose token expired are closed with `token_expired`
+///
+/// a token expires with the leeway of the join, `exp + leeway`; `token_expiring` is pushed once per token, `refresh_window`
+/// before that; SSE streams can't refresh, they just end
+pub async fn expire_tokens(state: Arc<State>) {
+    let mut warned: HashSet<(String, usize)> = HashSet::new(); // agent_id, exp
+    let mut interval = tokio::time::interval(EXPIRY_TICK);
+    loop {
+        interval.tick().await;
+        let (agents, conn_ids) = {
+            let ctl = state.ctl.lock().await;
+            let agents = ctl
+                .agents
+                .lock()
+                .await
+                .values()
+                .filter_map(|agent| Some((agent.id.clone(), agent.channel.clone(), agent.claims.as_ref()?.exp)))
+                .collect::<Vec<_>>();
+            (agents, ctl.conn_ids().await)
+        };
+        warned.retain(|(agent_id, exp)| agents.iter().any(|(id, _, agent_exp)| id == agent_id && agent_exp == exp));
+
+        let now = now();
+        let refresh_window = state.token_refresh_window.as_secs() as usize;
+        let leeway = state.jwt.leeway() as usize;
+        for (agent_id, topic, exp) in agents {
+            if exp + leeway < now {
+                info!("EXPIRY / {} token expired at {}", agent_id, exp);
+                let conn_id = agent_id.split(':').next().unwrap_or_default();
+                if conn_ids.iter().any(|id| id == conn_id) {
+                    close_agent(&state, &agent_id, &topic, "token_expired").await;
+                } else {
+                    let close = ChannelMessage::Close {
+                        code: CLOSE_POLICY_VIOLATION,
+                        reason: "token expired".into(),
+                    };
+                    state.ctl.lock().await.agent_send(&agent_id, close).await;
+                }
+            } else if exp + leeway <= now + refresh_window && warned.insert((agent_id.clone(), exp)) {
+                let message = ServerMessage {
+                    join_ref: None, // set by the relay
+                    event_ref: "0".into(),
+                    topic,
+                    event: "token_expiring".into(),
+                    payload: ServerPayload::ServerJsonValue(json!({"exp": exp})),
+                };
+                state.ctl.lock().await.agent_send(&agent_id, ChannelMessage::Reply(message)).await;
+            }
+        }
+    }
+}
+
+/// the agent goes on with a new token of the same user, its new `exp`, or the reason it's refused
+///
+/// the new token has to grant the topic like at the join, the pushes are checked against it from then on,
+/// the handler's socket gets its claims and assigns
+pub async fn refresh_token(
+    state: &Arc<State>, agent_id: &str, token: &str, redis_conn: Option<MultiplexedConnection>,
+) -> Result<usize, &'static str> {
+    let agent = state
+        .ctl
+        .lock()
+        .await
+        .agents
+        .lock()
+        .await
+        .get(agent_id)
+        .map(|agent| (agent.claims.clone(), agent.channel.clone()));
+    let Some((current, topic)) = agent else {
+        return Err("not_joined");
+    };
+    let claims = state.jwt.decode(token).await.map_err(|e| {
+        warn!("REFRESH / {} invalid token: {}", agent_id, e);
+        "invalid_token"
+    })?;
+    if current.is_some_and(|current| current.id != claims.id) {
+        warn!("REFRESH / {} token of another user: {}", agent_id, claims.id);
+        return Err("invalid_token");
+    }
+    if !state.can_read(&claims, &topic) {
+        warn!("REFRESH / token of {} doesn't grant {}", claims.id, topic);
+        return Err("unauthorized");
+    }
+    if let Some(mut redis_conn) = redis_conn {
+        match is_revoked(&mut redis_conn, &claims).await {
+            Ok(false) => {}
+            Ok(true) => return Err("token_revoked"),
+            Err(e) => error!("REFRESH / fail to check the revocations: {}", e),
+        }
+    }
+
+    let exp = claims.exp;
+    let socket = state.sockets.lock().await.get(agent_id).cloned();
+    if let Some(socket) = socket {
+        let mut socket = socket.lock().await;
+        socket.assigns = claims.assigns.clone();
+        socket.claims = Some(claims.clone());
+    }
+    state.ctl.lock().await.agent_set_claims(agent_id, claims).await;
+    info!("REFRESH / {} token refreshed, exp: {}", agent_id, exp);
+    Ok(exp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{RedisForwarder, Socket};
+    use crate::utils::{Claims, TopicGrant};
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_expiry_and_refresh() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        let ctl = state.ctl.lock().await;
+        ctl.conn_add("c1".into(), "websocket", None).await;
+        ctl.channel_add("room".into(), None).await;
+        // past `exp` but within the 60s leeway, and past the leeway
+        for (agent_id, exp) in [("c1:room:1", now() - 30), ("c1:room:2", now() - 61)] {
+            ctl.agent_add(agent_id.into(), None).await;
+            ctl.channel_join("room", agent_id.into(), "alice".into()).await.unwrap();
+            let claims = Claims {
+                id: "alice".into(),
+                exp,
+                ..Claims::default()
+            };
+            ctl.agent_set_claims(agent_id, claims).await;
+        }
+        let mut conn_rx = ctl.conn_rx("c1".into()).await.unwrap();
+        let mut agent_rx = ctl.agent_rx("c1:room:1".into()).await.unwrap();
+        drop(ctl);
+
+        let sweeper = tokio::spawn(expire_tokens(state.clone()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sweeper.abort();
+
+        let Ok(ChannelMessage::Reply(expiring)) = agent_rx.try_recv() else {
+            panic!("token_expiring")
+        };
+        assert_eq!(expiring.event, "token_expiring");
+        let Ok(ChannelMessage::Reply(close)) = conn_rx.try_recv() else {
+            panic!("phx_close")
+        };
+        let ServerPayload::ServerJsonValue(payload) = close.payload else {
+            panic!("json payload")
+        };
+        assert_eq!((close.event.as_str(), close.join_ref.as_deref()), ("phx_close", Some("2")));
+        assert_eq!(payload, json!({"reason": "token_expired"}));
+        assert!(!state.ctl.lock().await.agents.lock().await.contains_key("c1:room:2"));
+
+        let token = state
+            .jwt
+            .sign(
+                Claims {
+                    id: "alice".into(),
+                    channel: "room".into(),
+                    ..Claims::default()
+                },
+                600,
+            )
+            .await
+            .unwrap();
+        let exp = refresh_token(&state, "c1:room:1", &token, None).await.unwrap();
+        assert!(exp > now() + 500);
+        let agents = state
+            .ctl
+            .lock()
+            .await
+            .agents
+            .lock()
+            .await
+            .get("c1:room:1")
+            .map(|agent| agent.claims.clone());
+        assert_eq!(agents.flatten().map(|claims| claims.exp), Some(exp));
+
+        // the forwarded envelope carries the assigns of the new token
+        let socket = Socket::new(state.clone(), "room".into(), "c1:room:1".into(), "alice".into(), Some("1".into()), None);
+        state.sockets.lock().await.insert("c1:room:1".into(), Arc::new(Mutex::new(socket)));
+        let refreshed = Claims {
+            id: "alice".into(),
+            assigns: serde_json::from_value(json!({"role": "admin"})).unwrap(),
+            ..Claims::default()
+        };
+        let refreshed = state.jwt.sign(refreshed, 600).await.unwrap();
+        refresh_token(&state, "c1:room:1", &refreshed, None).await.unwrap();
+        let socket = state.sockets.lock().await.get("c1:room:1").cloned().unwrap();
+        let socket = socket.lock().await;
+        assert_eq!(socket.assigns, serde_json::from_value::<serde_json::Map<_, _>>(json!({"role": "admin"})).unwrap());
+        assert_eq!(
+            RedisForwarder::envelope(&socket, &json!({"body": "hi"})),
+            json!({"user": "alice", "assigns": {"role": "admin"}, "payload": {"body": "hi"}})
+        );
+        drop(socket);
+
+        let bob = state
+            .jwt
+            .sign(
+                Claims {
+                    id: "bob".into(),
+                    channel: "room".into(),
+                    ..Claims::default()
+                },
+                600,
+            )
+            .await
+            .unwrap();
+        assert_eq!(refresh_token(&state, "c1:room:1", &bob, None).await, Err("invalid_token"));
+        assert_eq!(refresh_token(&state, "c1:room:1", "garbage", None).await, Err("invalid_token"));
+        assert_eq!(refresh_token(&state, "c1:room:2", &token, None).await, Err("not_joined"));
+
+        let lobby = Claims {
+            id: "alice".into(),
+            exp: now() + 600,
+            topics: vec![TopicGrant {
+                topic: "lobby".into(),
+                read: true,
+                write: true,
+                events: vec![],
+            }],
+            ..Claims::default()
+        };
+        let lobby = jsonwebtoken::encode(&Default::default(), &lobby, &jsonwebtoken::EncodingKey::from_secret(b"secret")).unwrap();
+        assert_eq!(refresh_token(&state, "c1:room:1", &lobby, None).await, Err("unauthorized"), "room isn't granted");
+        *state.topic_policies.write().unwrap() = vec![TopicGrant {
+            topic: "room".into(),
+            read: false,
+            write: false,
+            events: vec![],
+        }];
+        assert_eq!(refresh_token(&state, "c1:room:1", &token, None).await, Err("unauthorized"), "closed by the policy");
+    }
+}
@@ -0,0 +1,393 @@
+use async_trait::async_trait;
+use redis::{aio::MultiplexedConnection, RedisResult};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::api::{broadcast_across_nodes, BroadcastRequest, TopicBroadcast};
+use crate::channel::{ChannelError, ChannelMessage};
+use crate::inbound::ReceiveError;
+use crate::utils::{topic_matches, Claims};
+use crate::websocket::{publish_event, ServerMessage, ServerPayload, State};
+
+/// in-process channel logic, like a Phoenix channel module (`join/3`, `handle_in/3`, `terminate/2`)
+///
+/// registered per topic pattern with `Handlers::register`, topics without a handler use `RedisForwarder`
+#[async_trait]
+pub trait ChannelHandler: Send + Sync {
+    /// accept the join with a reply merged into `{"id": agent_id}`, or reject it with a reason
+    async fn join(&self, _topic: &str, _payload: &serde_json::Value, _socket: &mut Socket) -> Result<serde_json::Value, serde_json::Value> {
+        Ok(json!({}))
+    }
+
+    /// a push from the client, any event but `phx_join`, `phx_leave` and `heartbeat`
+    async fn handle_in(&self, event: &str, payload: &serde_json::Value, socket: &mut Socket) -> Reply;
+
+    /// events passed to `handle_out` before they're sent to an agent, other events are sent unchanged
+    fn intercept(&self) -> &[&str] {
+        &[]
+    }
+
+    /// rewrite an intercepted event for one agent, e.g. by its `external_id` or `assigns`; `None` drops it
+    async fn handle_out(&self, _event: &str, payload: serde_json::Value, _socket: &Socket) -> Option<serde_json::Value> {
+        Some(payload)
+    }
+
+    /// the agent is gone, `reason` is `leave`, `closed`, `kicked`, `removed` or `shutdown`
+    async fn terminate(&self, _reason: &str, _socket: &mut Socket) {}
+}
+
+/// answer to a push, sent as `phx_reply` with the push's ref
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    NoReply,
+    Ok(serde_json::Value),
+    Error(serde_json::Value),
+}
+
+/// the default handler, every push is published to redis as `from:{topic}:{event}`
+///
+/// the message is always `{"user": id, "assigns": {...}, "payload": payload}`, `assigns` of the token, empty without any
+pub struct RedisForwarder;
+
+#[async_trait]
+impl ChannelHandler for RedisForwarder {
+    async fn handle_in(&self, event: &str, payload: &serde_json::Value, socket: &mut Socket) -> Reply {
+        let redis_topic = format!("from:{}:{}", socket.topic, event);
+        let message = RedisForwarder::envelope(socket, payload).to_string();
+        match socket.publish(redis_topic, message).await {
+            Ok(()) => Reply::NoReply,
+            Err(e) => Reply::Error(ReceiveError::Redis(e).report(socket.conn_id())),
+        }
+    }
+}
+
+impl RedisForwarder {
+    /// the published message, with the claims of the agent's current token
+    pub(crate) fn envelope(socket: &Socket, payload: &serde_json::Value) -> serde_json::Value {
+        let (user, assigns) = match &socket.claims {
+            Some(claims) => (&claims.id, json!(claims.assigns)),
+            None => (&socket.external_id, json!({})),
+        };
+        json!({"user": user, "assigns": assigns, "payload": payload})
+    }
+}
+
+/// handlers by topic pattern, `room:*` matches every topic starting with `room:`, other patterns match exactly
+pub struct Handlers {
+    routes: Vec<(String, Arc<dyn ChannelHandler>)>,
+    fallback: Arc<dyn ChannelHandler>,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        Handlers {
+            routes: vec![],
+            fallback: Arc::new(RedisForwarder),
+        }
+    }
+}
+
+impl Handlers {
+    /// the first registered pattern matching a topic wins
+    pub fn register(&mut self, pattern: impl Into<String>, handler: Arc<dyn ChannelHandler>) {
+        self.routes.push((pattern.into(), handler));
+    }
+
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|(pattern, _)| pattern.as_str())
+    }
+
+    pub fn find(&self, topic: &str) -> Arc<dyn ChannelHandler> {
+        self.routes
+            .iter()
+            .find(|(pattern, _)| topic_matches(pattern, topic))
+            .map_or_else(|| self.fallback.clone(), |(_, handler)| handler.clone())
+    }
+}
+
+/// a joined agent as seen by its handler, `assigns` is kept between calls
+pub struct Socket {
+    pub topic: String,
+    pub agent_id: String, // {conn_id}:{topic}:{join_ref}
+    pub external_id: String,
+    pub join_ref: Option<String>,
+    pub assigns: serde_json::Map<String, serde_json::Value>, // the token's assigns on join, replaced on `phx_refresh`
+    pub claims: Option<Claims>,                              // of the token the agent joined or refreshed with
+    state: Arc<State>,
+    redis_conn: Option<MultiplexedConnection>,
+}
+
+impl Socket {
+    pub fn new(
+        state: Arc<State>, topic: String, agent_id: String, external_id: String, join_ref: Option<String>, redis_conn: Option<MultiplexedConnection>,
+    ) -> Self {
+        Socket {
+            topic,
+            agent_id,
+            external_id,
+            join_ref,
+            assigns: serde_json::Map::new(),
+            claims: None,
+            state,
+            redis_conn,
+        }
+    }
+
+    pub fn conn_id(&self) -> &str {
+        self.agent_id.split(':').next().unwrap_or_default()
+    }
+
+    /// send an event to this client only
+    pub async fn push(&self, event: &str, payload: serde_json::Value) -> Result<usize, ChannelError> {
+        let message = ServerMessage {
+            join_ref: self.join_ref.clone(),
+            event_ref: "0".into(),
+            topic: self.topic.clone(),
+            event: event.to_string(),
+            payload: ServerPayload::ServerJsonValue(payload),
+        };
+        self.state
+            .ctl
+            .lock()
+            .await
+            .conn_send(self.conn_id().to_string(), ChannelMessage::Reply(message))
+            .await
+    }
+
+    /// send an event to every agent of the topic, on every node; returns the number of agents on this node
+    pub async fn broadcast(&self, event: &str, payload: serde_json::Value) -> usize {
+        self.broadcast_with(event, payload, None).await
+    }
+
+    /// like `broadcast`, except this agent
+    pub async fn broadcast_from(&self, event: &str, payload: serde_json::Value) -> usize {
+        self.broadcast_with(event, payload, Some(self.agent_id.clone())).await
+    }
+
+    async fn broadcast_with(&self, event: &str, payload: serde_json::Value, exclude: Option<String>) -> usize {
+        let topic_broadcast = TopicBroadcast {
+            topic: self.topic.clone(),
+            broadcast: BroadcastRequest {
+                event: event.to_string(),
+                payload,
+                exclude,
+            },
+        };
+        broadcast_across_nodes(self.state.clone(), topic_broadcast).await.delivered
+    }
+
+    /// publish to redis with the connection's redis connection, connected on first use
+    pub async fn publish(&mut self, redis_topic: String, message: String) -> RedisResult<()> {
+        let redis_conn = match self.redis_conn.as_mut() {
+            Some(redis_conn) => redis_conn,
+            None => self.redis_conn.insert(self.state.redis_client.get_multiplexed_async_connection().await?),
+        };
+        publish_event(redis_conn, redis_topic, message).await
+    }
+}
+
+/// sockets of the joined agents, agent_id -> socket
+pub type Sockets = Mutex<HashMap<String, Arc<Mutex<Socket>>>>;
+
+/// an outgoing message for an agent through its handler's `handle_out`, `None` when the handler drops it
+///
+/// agents without a socket (SSE) and non-JSON payloads are left unchanged
+pub async fn handle_out(state: &State, agent_id: &str, mut message: ChannelMessage) -> Option<ChannelMessage> {
+    let Some(reply) = message.reply_mut() else {
+        return Some(message);
+    };
+    let handler = state.handlers.find(&reply.topic);
+    if !handler.intercept().contains(&reply.event.as_str()) {
+        return Some(message);
+    }
+    let (Some(socket), ServerPayload::ServerJsonValue(payload)) = (state.sockets.lock().await.get(agent_id).cloned(), &reply.payload) else {
+        return Some(message);
+    };
+    let socket = socket.lock().await;
+    match handler.handle_out(&reply.event, payload.clone(), &socket).await {
+        Some(payload) => reply.payload = ServerPayload::ServerJsonValue(payload),
+        None => {
+            debug!("SOCKET / {}, {} dropped by the handler", agent_id, reply.event);
+            return None;
+        }
+    }
+    Some(message)
+}
+
+/// remove the agent's socket and call `terminate` of its handler
+///
+/// must not be called with `ctl` locked, handlers may broadcast
+pub async fn terminate(state: &State, agent_id: &str, reason: &str) {
+    let Some(socket) = state.sockets.lock().await.remove(agent_id) else {
+        return;
+    };
+    let mut socket = socket.lock().await;
+    debug!("SOCKET / {} terminates, reason: {}", agent_id, reason);
+    state.handlers.find(&socket.topic).terminate(reason, &mut socket).await;
+}
+
+/// `terminate` every agent of a connection
+pub async fn terminate_conn(state: &State, conn_id: &str, reason: &str) {
+    let prefix = format!("{}:", conn_id);
+    let agent_ids = state
+        .sockets
+        .lock()
+        .await
+        .keys()
+        .filter(|agent_id| agent_id.starts_with(&prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+    for agent_id in agent_ids {
+        terminate(state, &agent_id, reason).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Claims;
+    use crate::websocket::{handle_join, RequestMessage};
+
+    struct Echo;
+
+    #[async_trait]
+    impl ChannelHandler for Echo {
+        async fn join(&self, _topic: &str, payload: &serde_json::Value, socket: &mut Socket) -> Result<serde_json::Value, serde_json::Value> {
+            if payload["password"] != "open sesame" {
+                return Err(json!({"reason": "unauthorized"}));
+            }
+            socket.assigns.insert("count".into(), json!(0));
+            Ok(json!({"welcome": true}))
+        }
+
+        async fn handle_in(&self, event: &str, payload: &serde_json::Value, socket: &mut Socket) -> Reply {
+            let count = socket.assigns["count"].as_u64().unwrap() + 1;
+            socket.assigns.insert("count".into(), json!(count));
+            Reply::Ok(json!({"event": event, "payload": payload, "count": count}))
+        }
+    }
+
+    #[test]
+    fn test_handler_patterns() {
+        let mut handlers = Handlers::default();
+        let echo: Arc<dyn ChannelHandler> = Arc::new(Echo);
+        handlers.register("room:*", echo.clone());
+        handlers.register("lobby", echo.clone());
+
+        assert!(Arc::ptr_eq(&handlers.find("room:1"), &echo));
+        assert!(Arc::ptr_eq(&handlers.find("lobby"), &echo));
+        assert!(!Arc::ptr_eq(&handlers.find("lobby:1"), &echo));
+        assert!(!Arc::ptr_eq(&handlers.find("system"), &echo));
+    }
+
+    #[tokio::test]
+    async fn test_handler_socket_assigns() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        let mut socket = Socket::new(state.clone(), "room:1".into(), "c1:room:1:1".into(), "alice".into(), Some("1".into()), None);
+        assert_eq!(socket.conn_id(), "c1");
+
+        assert_eq!(Echo.join("room:1", &json!({}), &mut socket).await, Err(json!({"reason": "unauthorized"})));
+        assert_eq!(Echo.join("room:1", &json!({"password": "open sesame"}), &mut socket).await, Ok(json!({"welcome": true})));
+        Echo.handle_in("ping", &json!({}), &mut socket).await;
+        let reply = Echo.handle_in("ping", &json!({"n": 2}), &mut socket).await;
+        assert_eq!(reply, Reply::Ok(json!({"event": "ping", "payload": {"n": 2}, "count": 2})));
+    }
+
+    struct Owned;
+
+    #[async_trait]
+    impl ChannelHandler for Owned {
+        async fn join(&self, _topic: &str, payload: &serde_json::Value, socket: &mut Socket) -> Result<serde_json::Value, serde_json::Value> {
+            socket.assigns.insert("kind".into(), payload["kind"].clone());
+            Ok(json!({}))
+        }
+
+        async fn handle_in(&self, _event: &str, _payload: &serde_json::Value, _socket: &mut Socket) -> Reply {
+            Reply::NoReply
+        }
+
+        fn intercept(&self) -> &[&str] {
+            &["update"]
+        }
+
+        async fn handle_out(&self, _event: &str, mut payload: serde_json::Value, socket: &Socket) -> Option<serde_json::Value> {
+            if !socket.assigns["kind"].is_null() && payload["kind"] != socket.assigns["kind"] {
+                return None;
+            }
+            if payload["owner"] != socket.external_id.as_str() {
+                payload.as_object_mut()?.remove("secret");
+            }
+            Some(payload)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_handle_out() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let mut handlers = Handlers::default();
+        handlers.register("doc:*", Arc::new(Owned));
+        let state = Arc::new(State {
+            handlers,
+            ..State::new(redis_client, "secret".into())
+        });
+        for (agent_id, external_id, join_payload) in [("c1:doc:1:1", "alice", json!({})), ("c2:doc:1:1", "bob", json!({"kind": "note"}))] {
+            let mut socket = Socket::new(state.clone(), "doc:1".into(), agent_id.into(), external_id.into(), Some("1".into()), None);
+            Owned.join("doc:1", &join_payload, &mut socket).await.unwrap();
+            state.sockets.lock().await.insert(agent_id.into(), Arc::new(Mutex::new(socket)));
+        }
+        let message = |event: &str, payload: serde_json::Value| {
+            ChannelMessage::Reply(ServerMessage {
+                join_ref: None,
+                event_ref: "1".into(),
+                topic: "doc:1".into(),
+                event: event.into(),
+                payload: ServerPayload::ServerJsonValue(payload),
+            })
+        };
+        let payload_of = |message: Option<ChannelMessage>| match message.and_then(ChannelMessage::into_reply).map(|reply| reply.payload) {
+            Some(ServerPayload::ServerJsonValue(payload)) => Some(payload),
+            _ => None,
+        };
+
+        let update = json!({"owner": "alice", "kind": "todo", "secret": 42});
+        assert_eq!(payload_of(handle_out(&state, "c1:doc:1:1", message("update", update.clone())).await), Some(update.clone()));
+        assert_eq!(payload_of(handle_out(&state, "c2:doc:1:1", message("update", update.clone())).await), None, "filtered by kind");
+
+        let note = json!({"owner": "alice", "kind": "note", "secret": 42});
+        let hidden = json!({"owner": "alice", "kind": "note"});
+        assert_eq!(payload_of(handle_out(&state, "c2:doc:1:1", message("update", note.clone())).await), Some(hidden));
+        assert_eq!(payload_of(handle_out(&state, "c2:doc:1:1", message("other", note.clone())).await), Some(note), "not intercepted");
+    }
+
+    #[tokio::test]
+    async fn test_handler_join_params() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let mut handlers = Handlers::default();
+        handlers.register("doc:*", Arc::new(Owned));
+        let state = Arc::new(State {
+            handlers,
+            ..State::new(redis_client, "secret".into())
+        });
+        state.ctl.lock().await.conn_add("c1".into(), "websocket", None).await;
+        let token = state
+            .jwt
+            .sign(
+                Claims {
+                    id: "alice".into(),
+                    channel: "doc:1".into(),
+                    ..Claims::default()
+                },
+                60,
+            )
+            .await
+            .unwrap();
+        let join: RequestMessage = serde_json::from_value(json!(["1", "1", "doc:1", "phx_join", {"token": token, "kind": "note"}])).unwrap();
+        handle_join(None, &join, state.clone(), "c1", None).await.unwrap();
+
+        let socket = state.sockets.lock().await.get("c1:doc:1:1").cloned().unwrap();
+        assert_eq!(socket.lock().await.assigns["kind"], "note", "the params of the join reach the handler");
+    }
+}
@@ -0,0 +1,87 @@
+use axum::{
+    extract::{Json, State as AxumState},
+    http::StatusCode,
+};
+use serde_json::json;
+use std::{sync::atomic::Ordering, sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::websocket::State;
+
+/// channels whose redis listeners must run for the node to be ready
+pub const REQUIRED_LISTENERS: [&str; 3] = ["phoenix", "admin", "system"];
+
+const REDIS_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `GET /healthz`, liveness, the process is up and serving requests
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(json!({"status": "ok"}))
+}
+
+/// `GET /readyz`, readiness, 503 unless redis answers `PING`, the special listeners run and the server isn't draining
+pub async fn readyz(AxumState(state): AxumState<Arc<State>>) -> (StatusCode, Json<serde_json::Value>) {
+    let redis = match tokio::time::timeout(REDIS_PING_TIMEOUT, redis_ping(&state.redis_client)).await {
+        Ok(Ok(())) => json!({"status": "ok"}),
+        Ok(Err(e)) => json!({"status": "error", "error": e.to_string()}),
+        Err(_) => json!({"status": "error", "error": "timeout"}),
+    };
+    let redis_ok = redis["status"] == "ok";
+
+    let mut listeners = serde_json::Map::new();
+    {
+        let ctl = state.ctl.lock().await;
+        let channels = ctl.channels.lock().await;
+        for name in REQUIRED_LISTENERS {
+            let status = channels.get(name).map_or("none", |channel| channel.listener_status());
+            listeners.insert(name.to_string(), json!(status));
+        }
+    }
+    let listeners_ok = listeners.values().all(|status| status == "running");
+
+    let draining = state.draining.load(Ordering::SeqCst);
+
+    let ready = redis_ok && listeners_ok && !draining;
+    if !ready {
+        warn!("READY / not ready, redis: {}, listeners: {:?}, draining: {}", redis, listeners, draining);
+    }
+    let status_code = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "components": {
+            "redis": redis,
+            "listeners": listeners,
+            "draining": draining,
+        },
+    });
+    (status_code, Json(body))
+}
+
+async fn redis_ping(redis_client: &redis::Client) -> redis::RedisResult<()> {
+    let mut redis_conn = redis_client.get_multiplexed_async_connection().await?;
+    redis::cmd("PING").query_async::<String>(&mut redis_conn).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_health_readyz_components() {
+        // nothing listens there, the ping fails fast
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        state.ctl.lock().await.channel_add("system".into(), None).await;
+        state.draining.store(true, Ordering::SeqCst);
+
+        let Json(body) = healthz().await;
+        assert_eq!(body["status"], "ok");
+
+        let (status_code, Json(body)) = readyz(AxumState(state)).await;
+        assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["components"]["redis"]["status"], "error");
+        assert_eq!(body["components"]["listeners"], json!({"phoenix": "none", "admin": "none", "system": "none"}));
+        assert_eq!(body["components"]["draining"], true);
+    }
+}
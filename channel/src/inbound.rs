@@ -0,0 +1,637 @@
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    sync::Arc,
+    time::Instant,
+};
+use tracing::{debug, warn};
+
+use crate::channel::CLOSE_MESSAGE_TOO_BIG;
+use crate::expiry::refresh_token;
+use crate::handler::Reply;
+use crate::metrics::METRICS;
+use crate::serializer::SerializerError;
+use crate::websocket::{handle_join, handle_leave, ok_reply, publish_event, reply, RequestMessage, RequestPayload, State};
+
+/// a step of the inbound pipeline, like a Tower layer for the parsed client messages
+///
+/// a middleware inspects or rewrites the message and passes it on with `next.run`, short-circuits by returning `Ok(())`,
+/// or rejects it with a reason, the client gets `{"status": "error", "response": reason}`
+#[async_trait]
+pub trait InboundMiddleware: Send + Sync {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value>;
+}
+
+/// failures of the receive path, logged with the conn id and counted by `channel_receive_errors_total`
+///
+/// a frame over the size limit closes the connection with `close_code`, other failures get an error reply when there's a ref to reply to
+#[derive(Debug)]
+pub enum ReceiveError {
+    Transport(String), // reading the socket failed, the connection is gone
+    FrameTooBig(usize),
+    Malformed(SerializerError),
+    MissingJoinRef,
+    NotJoined,
+    Unauthorized,
+    Redis(redis::RedisError),
+}
+
+impl Error for ReceiveError {}
+
+impl Display for ReceiveError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiveError::Transport(e) => write!(formatter, "<Transport: {}>", e),
+            ReceiveError::FrameTooBig(size) => write!(formatter, "<FrameTooBig: {} bytes>", size),
+            ReceiveError::Malformed(e) => write!(formatter, "<Malformed: {}>", e),
+            ReceiveError::MissingJoinRef => write!(formatter, "<MissingJoinRef: phx_join and phx_leave need a join_ref>"),
+            ReceiveError::NotJoined => write!(formatter, "<NotJoined: the topic isn't joined>"),
+            ReceiveError::Unauthorized => write!(formatter, "<Unauthorized: the event can't be pushed to the topic>"),
+            ReceiveError::Redis(e) => write!(formatter, "<Redis: {}>", e),
+        }
+    }
+}
+
+impl ReceiveError {
+    /// the `reason` of the error reply and the metrics label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ReceiveError::Transport(_) => "transport",
+            ReceiveError::FrameTooBig(_) => "frame_too_big",
+            ReceiveError::Malformed(_) => "malformed",
+            ReceiveError::MissingJoinRef => "join_ref_required",
+            ReceiveError::NotJoined => "not_joined",
+            ReceiveError::Unauthorized => "unauthorized",
+            ReceiveError::Redis(_) => "redis_unavailable",
+        }
+    }
+
+    /// the close code of the errors that end the connection
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            ReceiveError::FrameTooBig(_) => Some(CLOSE_MESSAGE_TOO_BIG),
+            _ => None,
+        }
+    }
+
+    /// log and count, the response of the error reply
+    pub fn report(&self, conn_id: &str) -> serde_json::Value {
+        warn!("RECEIVE / conn: {}, {}", conn_id, self);
+        METRICS.receive_errors.with_label_values(&[self.reason()]).inc();
+        json!({"reason": self.reason()})
+    }
+}
+
+/// the connection a message came from
+pub struct Context {
+    pub state: Arc<State>,
+    pub conn_id: String,
+    pub user_token: Option<String>, // token of the connection, for joins without one
+    redis_conn: Option<MultiplexedConnection>,
+}
+
+impl Context {
+    pub fn new(state: Arc<State>, conn_id: String, user_token: Option<String>, redis_conn: Option<MultiplexedConnection>) -> Self {
+        Context {
+            state,
+            conn_id,
+            user_token,
+            redis_conn,
+        }
+    }
+
+    /// publish to redis with the connection's redis connection, connected on first use
+    pub async fn publish(&mut self, redis_topic: String, message: String) -> Result<(), ReceiveError> {
+        let redis_conn = match self.redis_conn.as_mut() {
+            Some(redis_conn) => redis_conn,
+            None => {
+                let redis_conn = self
+                    .state
+                    .redis_client
+                    .get_multiplexed_async_connection()
+                    .await
+                    .map_err(ReceiveError::Redis)?;
+                self.redis_conn.insert(redis_conn)
+            }
+        };
+        publish_event(redis_conn, redis_topic, message).await.map_err(ReceiveError::Redis)
+    }
+}
+
+/// the rest of the pipeline
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn InboundMiddleware>],
+}
+
+impl Next<'_> {
+    pub async fn run(self, rm: RequestMessage, ctx: &mut Context) -> Result<(), serde_json::Value> {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.call(rm, ctx, Next { middlewares: rest }).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// size and join limits of the client messages
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MessageLimits {
+    pub max_frame_size: usize,                       // bytes of a websocket message or long-poll line, closed with 1009 above
+    pub max_payload_size: usize,                     // bytes of a payload as JSON, rejected above
+    pub event_payload_sizes: HashMap<String, usize>, // `max_payload_size` by event
+    pub max_joins: usize,                            // joined topics per connection
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_frame_size: 1024 * 1024,
+            max_payload_size: 256 * 1024,
+            event_payload_sizes: HashMap::new(),
+            max_joins: 100,
+        }
+    }
+}
+
+/// operator middlewares followed by the built-in steps: limits, heartbeat, join, leave, refresh, authorize, metrics and dispatch
+pub struct Pipeline {
+    middlewares: Vec<Arc<dyn InboundMiddleware>>,
+    layers: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            middlewares: vec![
+                Arc::new(Limits),
+                Arc::new(Heartbeat),
+                Arc::new(Join),
+                Arc::new(Leave),
+                Arc::new(Refresh),
+                Arc::new(Authorize),
+                Arc::new(Metrics),
+                Arc::new(Dispatch),
+            ],
+            layers: 0,
+        }
+    }
+}
+
+impl Pipeline {
+    /// add a middleware before the built-in steps, after the ones added before
+    pub fn layer(&mut self, middleware: Arc<dyn InboundMiddleware>) {
+        self.middlewares.insert(self.layers, middleware);
+        self.layers += 1;
+    }
+
+    /// run a message through the pipeline, a rejection is replied to the client
+    pub async fn run(&self, rm: RequestMessage, ctx: &mut Context) {
+        let (join_ref, event_ref, topic) = (rm.join_ref.clone(), rm.event_ref.clone(), rm.topic.clone());
+        let next = Next {
+            middlewares: &self.middlewares,
+        };
+        if let Err(reason) = next.run(rm, ctx).await {
+            warn!("INBOUND / conn: {}, {} rejected: {}", ctx.conn_id, topic, reason);
+            reply(&ctx.conn_id, join_ref, &event_ref, &topic, "error", reason, ctx.state.clone()).await;
+        }
+    }
+}
+
+/// payloads over `max_payload_size` and joins over `max_joins` are rejected
+struct Limits;
+
+#[async_trait]
+impl InboundMiddleware for Limits {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        let (max_payload_size, max_joins) = {
+            let limits = ctx.state.message_limits.read().unwrap();
+            (limits.event_payload_sizes.get(&rm.event).copied().unwrap_or(limits.max_payload_size), limits.max_joins)
+        };
+        let payload_size = serde_json::to_vec(&rm.payload).map_or(0, |payload| payload.len());
+        if payload_size > max_payload_size {
+            METRICS.limit_exceeded.with_label_values(&["payload"]).inc();
+            return Err(json!({"reason": "payload_too_large", "max_size": max_payload_size}));
+        }
+
+        if rm.event == "phx_join" {
+            let prefix = format!("{}:", ctx.conn_id);
+            let joins = ctx
+                .state
+                .sockets
+                .lock()
+                .await
+                .keys()
+                .filter(|agent_id| agent_id.starts_with(&prefix))
+                .count();
+            if joins >= max_joins {
+                METRICS.limit_exceeded.with_label_values(&["joins"]).inc();
+                return Err(json!({"reason": "too_many_joins", "max_joins": max_joins}));
+            }
+        }
+        next.run(rm, ctx).await
+    }
+}
+
+/// `heartbeat` of `phoenix`, acked and published to redis with the conn_id
+struct Heartbeat;
+
+#[async_trait]
+impl InboundMiddleware for Heartbeat {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        if rm.topic == "phoenix" && rm.event == "heartbeat" {
+            ctx.state.ctl.lock().await.conn_heartbeat(&ctx.conn_id).await;
+
+            // it continues to publish events to the Redis
+            ok_reply(&ctx.conn_id, None, &rm.event_ref, "phoenix", ctx.state.clone()).await;
+
+            // payload 是 {}, 所以这里增加一些信息
+            let message = format!(r#"{{"conn_id": "{}"}}"#, ctx.conn_id); // double {{ and }} to escape
+            if let Err(e) = ctx.publish("from:phoenix:heartbeat".to_string(), message).await {
+                e.report(&ctx.conn_id); // acked already
+            }
+        }
+        next.run(rm, ctx).await
+    }
+}
+
+/// `phx_join`, an accepted join is published to redis afterwards
+struct Join;
+
+#[async_trait]
+impl InboundMiddleware for Join {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        if rm.event == "phx_join" {
+            if rm.join_ref.is_none() {
+                return Err(ReceiveError::MissingJoinRef.report(&ctx.conn_id));
+            }
+            // 启动一个新的 relay task (agent rx => conn tx), 需要在agent leave 的时候清除
+            // connection 中每个 join 都会产生这个 task；connection 断掉的时候会自动退出
+            let started = Instant::now();
+            match handle_join(ctx.user_token.clone(), &rm, ctx.state.clone(), &ctx.conn_id, ctx.redis_conn.clone()).await {
+                Ok(_relay_task) => METRICS.join_duration.observe(started.elapsed().as_secs_f64()),
+                Err(e) => {
+                    // a refused join isn't published
+                    METRICS.join_failure(&e);
+                    return Ok(());
+                }
+            }
+            debug!("WS_RX / join processed");
+        }
+        next.run(rm, ctx).await
+    }
+}
+
+/// `phx_leave`, the leave is published to redis afterwards
+struct Leave;
+
+#[async_trait]
+impl InboundMiddleware for Leave {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        if rm.event == "phx_leave" {
+            let Some(join_ref) = rm.join_ref.clone() else {
+                return Err(ReceiveError::MissingJoinRef.report(&ctx.conn_id));
+            };
+            if let Err(e) = handle_leave(ctx.state.clone(), &ctx.conn_id, join_ref, &rm.event_ref, rm.topic.clone()).await {
+                return Err(e.report(&ctx.conn_id));
+            }
+            debug!("WS_RX / leave processed");
+        }
+        next.run(rm, ctx).await
+    }
+}
+
+/// `phx_refresh` with `{"token": ...}`, the agent goes on with the new token, replied with its `exp`; the token isn't published
+struct Refresh;
+
+#[async_trait]
+impl InboundMiddleware for Refresh {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        if rm.event != "phx_refresh" {
+            return next.run(rm, ctx).await;
+        }
+        let Some(join_ref) = rm.join_ref.clone() else {
+            return Err(ReceiveError::MissingJoinRef.report(&ctx.conn_id));
+        };
+        let RequestPayload::Join { token, .. } = &rm.payload else {
+            return Err(json!({"reason": "invalid_token"}));
+        };
+        let agent_id = format!("{}:{}:{}", ctx.conn_id, rm.topic, join_ref);
+        let exp = refresh_token(&ctx.state, &agent_id, token, ctx.redis_conn.clone())
+            .await
+            .map_err(|reason| json!({"reason": reason}))?;
+        reply(&ctx.conn_id, rm.join_ref, &rm.event_ref, &rm.topic, "ok", json!({"exp": exp}), ctx.state.clone()).await;
+        Ok(())
+    }
+}
+
+/// pushes the topic policy or the grants of the agent's token don't allow aren't dispatched
+///
+/// a push needs the agent of its topic and join_ref on this connection, `not_joined` otherwise;
+/// accepted joins and leaves of joined agents pass, like the `heartbeat` of `phoenix`
+struct Authorize;
+
+#[async_trait]
+impl InboundMiddleware for Authorize {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        // `Join` and `Leave` stop refused joins and leaves of agents that aren't joined
+        let joined_or_left = rm.event == "phx_join" || rm.event == "phx_leave";
+        if joined_or_left || (rm.topic == "phoenix" && rm.event == "heartbeat") {
+            return next.run(rm, ctx).await;
+        }
+        let agent_id = format!("{}:{}:{}", ctx.conn_id, rm.topic, rm.join_ref.clone().unwrap_or_default());
+        let Some(claims) = ctx
+            .state
+            .ctl
+            .lock()
+            .await
+            .agents
+            .lock()
+            .await
+            .get(&agent_id)
+            .map(|agent| agent.claims.clone())
+        else {
+            return Err(ReceiveError::NotJoined.report(&ctx.conn_id));
+        };
+        if !claims.is_some_and(|claims| ctx.state.can_push(&claims, &rm.topic, &rm.event)) {
+            return Err(ReceiveError::Unauthorized.report(&ctx.conn_id));
+        }
+        next.run(rm, ctx).await
+    }
+}
+
+struct Metrics;
+
+#[async_trait]
+impl InboundMiddleware for Metrics {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        METRICS.message_in(&rm.topic, &rm.event);
+        next.run(rm, ctx).await
+    }
+}
+
+/// pushes to a joined channel go to its handler, everything else is published to redis as `from:{topic}:{event}`
+struct Dispatch;
+
+#[async_trait]
+impl InboundMiddleware for Dispatch {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, _next: Next<'_>) -> Result<(), serde_json::Value> {
+        let state = ctx.state.clone();
+        let agent_id = format!("{}:{}:{}", ctx.conn_id, rm.topic, rm.join_ref.clone().unwrap_or_default());
+        let socket = state.sockets.lock().await.get(&agent_id).cloned();
+        if let (Some(socket), false) = (socket, ["phx_join", "phx_leave", "heartbeat"].contains(&rm.event.as_str())) {
+            let payload = serde_json::to_value(&rm.payload).unwrap_or_default();
+            let mut socket = socket.lock().await;
+            match state.handlers.find(&rm.topic).handle_in(&rm.event, &payload, &mut socket).await {
+                Reply::NoReply => {}
+                Reply::Ok(response) => reply(&ctx.conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "ok", response, state.clone()).await,
+                Reply::Error(response) => return Err(response),
+            }
+            return Ok(());
+        }
+
+        // all events are dispatched to reids
+        // iredis --url redis://localhost:6379 psubscribe 'from*'
+        let redis_topic = format!("from:{}:{}", rm.topic, rm.event);
+        let message = serde_json::to_string(&rm.payload).unwrap_or_default();
+        match ctx.publish(redis_topic, message).await {
+            Ok(()) => Ok(()),
+            // joins and leaves have been replied to already
+            Err(e) if ["phx_join", "phx_leave", "heartbeat"].contains(&rm.event.as_str()) => {
+                e.report(&ctx.conn_id);
+                Ok(())
+            }
+            Err(e) => Err(e.report(&ctx.conn_id)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::ChannelMessage;
+    use crate::handler::Socket;
+    use crate::serializer::{Frame, Serializer};
+    use crate::utils::Claims;
+    use crate::websocket::{handle_message, RequestPayload, ServerMessage, ServerPayload};
+    use std::sync::RwLock;
+
+    /// rejects `forbidden`, tags the payloads with the conn_id
+    struct Guard;
+
+    #[async_trait]
+    impl InboundMiddleware for Guard {
+        async fn call(&self, mut rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+            if rm.event == "forbidden" {
+                return Err(json!({"reason": "forbidden"}));
+            }
+            if let RequestPayload::JsonValue(serde_json::Value::Object(payload)) = &mut rm.payload {
+                payload.insert("conn_id".into(), json!(ctx.conn_id));
+            }
+            next.run(rm, ctx).await
+        }
+    }
+
+    /// short-circuits, the message is sent back to the client
+    struct Echo;
+
+    #[async_trait]
+    impl InboundMiddleware for Echo {
+        async fn call(&self, rm: RequestMessage, ctx: &mut Context, _next: Next<'_>) -> Result<(), serde_json::Value> {
+            let payload = serde_json::to_value(&rm.payload).unwrap();
+            reply(&ctx.conn_id, rm.join_ref, &rm.event_ref, &rm.topic, "ok", payload, ctx.state.clone()).await;
+            Ok(())
+        }
+    }
+
+    fn request(event: &str, payload: serde_json::Value) -> RequestMessage {
+        serde_json::from_value(json!(["1", "2", "room", event, payload])).unwrap()
+    }
+
+    /// the agent `c1:{topic}:1` joined with the claims
+    async fn join(state: &State, topic: &str, claims: Claims) {
+        let agent_id = format!("c1:{}:1", topic);
+        let ctl = state.ctl.lock().await;
+        ctl.channel_add(topic.into(), None).await;
+        ctl.agent_add(agent_id.clone(), None).await;
+        ctl.channel_join(topic, agent_id.clone(), claims.id.clone()).await.unwrap();
+        ctl.agent_set_claims(&agent_id, claims).await;
+    }
+
+    async fn response_of(conn_rx: &mut tokio::sync::broadcast::Receiver<ChannelMessage>) -> serde_json::Value {
+        match conn_rx.recv().await.unwrap() {
+            ChannelMessage::Reply(message) => match message.payload {
+                ServerPayload::ServerJsonValue(payload) => payload,
+                payload => panic!("unexpected payload: {:?}", payload),
+            },
+            message => panic!("unexpected message: {}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_limits() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State {
+            message_limits: RwLock::new(MessageLimits {
+                max_payload_size: 16,
+                event_payload_sizes: HashMap::from([("upload".to_string(), 64)]),
+                max_joins: 1,
+                ..MessageLimits::default()
+            }),
+            ..State::new(redis_client, "secret".into())
+        });
+        state.ctl.lock().await.conn_add("c1".into(), "websocket", None).await;
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+        let pipeline = Pipeline::default();
+        let mut ctx = Context::new(state.clone(), "c1".into(), None, None);
+        join(&state, "room", Claims::default()).await;
+
+        let text = json!({"text": "a".repeat(32)});
+        pipeline.run(request("shout", text.clone()), &mut ctx).await;
+        let response = response_of(&mut conn_rx).await;
+        assert_eq!(response["response"], json!({"reason": "payload_too_large", "max_size": 16}));
+        pipeline.run(request("upload", text), &mut ctx).await;
+        let response = response_of(&mut conn_rx).await;
+        assert_eq!(response["response"], json!({"reason": "redis_unavailable"}), "published, bigger payloads for uploads");
+
+        let socket = Socket::new(state.clone(), "lobby".into(), "c1:lobby:1".into(), "alice".into(), Some("1".into()), None);
+        state
+            .sockets
+            .lock()
+            .await
+            .insert("c1:lobby:1".into(), Arc::new(tokio::sync::Mutex::new(socket)));
+        pipeline.run(request("phx_join", json!({"token": "t"})), &mut ctx).await;
+        let response = response_of(&mut conn_rx).await;
+        assert_eq!(response["response"], json!({"reason": "too_many_joins", "max_joins": 1}));
+    }
+
+    #[tokio::test]
+    async fn test_inbound_receive_errors() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State {
+            message_limits: RwLock::new(MessageLimits {
+                max_frame_size: 128,
+                ..MessageLimits::default()
+            }),
+            ..State::new(redis_client, "secret".into())
+        });
+        state.ctl.lock().await.conn_add("c1".into(), "websocket", None).await;
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+        let receive = async |text: &str| {
+            let frame = Frame::Text(text.to_string());
+            handle_message(state.clone(), None, "c1", Serializer::Json, &frame, None).await;
+        };
+        let malformed = METRICS.receive_errors.with_label_values(&["malformed"]).get();
+
+        receive(r#"[null, "1", "room", "phx_leave", {}]"#).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "join_ref_required"}));
+        receive(r#"["1", "2", "room", "phx_leave", {}]"#).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "not_joined"}));
+        receive(r#"["1", "3", "room", "shout", {}]"#).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "not_joined"}));
+        receive(r#"["1", "4", "room", "phx_join", {"token": "garbage"}]"#).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "invalid_token"}));
+        receive(r#"["1", "5", "room", "phx_join", {}]"#).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "invalid_token"}), "no token or client certificate");
+
+        join(&state, "room", Claims::default()).await;
+        receive(r#"["1", "6", "room", "phx_leave", {}]"#).await;
+        let Ok(ChannelMessage::Reply(ServerMessage {
+            payload: ServerPayload::ServerResponse(left),
+            ..
+        })) = conn_rx.recv().await
+        else {
+            panic!("replied")
+        };
+        assert_eq!(left.status, "ok");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(conn_rx.try_recv().is_err(), "left, the presence failing on redis isn't replied to again");
+
+        receive(r#"["1", "5", "room"]"#).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "malformed"}));
+        receive("not json").await;
+        assert!(conn_rx.try_recv().is_err(), "nothing to reply to");
+        receive(&format!(r#"["1", "4", "room", "shout", {{"text": "{}"}}]"#, "a".repeat(128))).await;
+        let Ok(ChannelMessage::Close { code, .. }) = conn_rx.recv().await else {
+            panic!("closed")
+        };
+        assert_eq!(code, CLOSE_MESSAGE_TOO_BIG);
+        assert_eq!(METRICS.receive_errors.with_label_values(&["malformed"]).get() - malformed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_pipeline() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        state.ctl.lock().await.conn_add("c1".into(), "websocket", None).await;
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+
+        let mut pipeline = Pipeline::default();
+        pipeline.layer(Arc::new(Guard));
+        pipeline.layer(Arc::new(Echo));
+        let mut ctx = Context::new(state.clone(), "c1".into(), None, None);
+
+        pipeline.run(request("forbidden", json!({})), &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await, json!({"status": "error", "response": {"reason": "forbidden"}}));
+
+        pipeline.run(request("shout", json!({"text": "hi"})), &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await, json!({"status": "ok", "response": {"text": "hi", "conn_id": "c1"}}));
+    }
+
+    #[tokio::test]
+    async fn test_inbound_authorize() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State {
+            topic_policies: RwLock::new(serde_json::from_value(json!([{"topic": "news:*", "write": false}])).unwrap()),
+            ..State::new(redis_client, "secret".into())
+        });
+        state.ctl.lock().await.conn_add("c1".into(), "websocket", None).await;
+        let claims = Claims {
+            id: "alice".into(),
+            topics: serde_json::from_value(json!([{"topic": "room", "write": true, "events": ["shout"]}, {"topic": "news:*", "write": true}]))
+                .unwrap(),
+            ..Claims::default()
+        };
+        join(&state, "room", claims.clone()).await;
+        join(&state, "news:today", claims.clone()).await;
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+        let pipeline = Pipeline::default();
+        let mut ctx = Context::new(state.clone(), "c1".into(), None, None);
+
+        pipeline.run(request("shout", json!({})), &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "redis_unavailable"}), "allowed, published");
+        pipeline.run(request("wave", json!({})), &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "unauthorized"}));
+
+        let news: RequestMessage = serde_json::from_value(json!(["1", "3", "news:today", "shout", {}])).unwrap();
+        pipeline.run(news, &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "unauthorized"}), "the policy is read-only");
+
+        for (push, reason) in [
+            (json!([null, "4", "room", "shout", {}]), "no join_ref"),
+            (json!(["9", "5", "room", "shout", {}]), "another join_ref"),
+            (json!(["1", "6", "lobby", "shout", {}]), "a topic that isn't joined"),
+        ] {
+            pipeline.run(serde_json::from_value(push).unwrap(), &mut ctx).await;
+            assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "not_joined"}), "{}", reason);
+        }
+        assert!(state.can_read(&Claims::default(), "news:today"));
+
+        let heartbeat: RequestMessage = serde_json::from_value(json!([null, "7", "room", "heartbeat", {}])).unwrap();
+        pipeline.run(heartbeat, &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "not_joined"}), "only phoenix heartbeats pass");
+        let leave: RequestMessage = serde_json::from_value(json!(["9", "8", "room", "phx_leave", {}])).unwrap();
+        pipeline.run(leave, &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "not_joined"}));
+
+        let token = state.jwt.sign(claims, 60).await.unwrap();
+        let refused: RequestMessage = serde_json::from_value(json!(["2", "9", "lobby", "phx_join", {"token": token}])).unwrap();
+        pipeline.run(refused, &mut ctx).await;
+        assert_eq!(response_of(&mut conn_rx).await["response"], json!({"reason": "unauthorized"}));
+        assert!(conn_rx.try_recv().is_err(), "replied once, not dispatched");
+    }
+}
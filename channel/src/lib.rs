@@ -1,3 +1,21 @@
+pub mod api;
 pub mod channel;
+pub mod config;
+pub mod expiry;
+pub mod handler;
+pub mod health;
+pub mod inbound;
+pub mod jwt;
+pub mod listen;
+pub mod longpoll;
+pub mod metrics;
+pub mod origin;
+pub mod ratelimit;
+pub mod revocation;
+pub mod serializer;
+pub mod server;
+pub mod shutdown;
+pub mod sse;
+pub mod tls;
 pub mod utils;
 pub mod websocket;
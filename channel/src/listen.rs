@@ -0,0 +1,320 @@
+use axum::{middleware, Router};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    fs::DirBuilder,
+    future::Future,
+    io::ErrorKind,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{info, warn};
+
+use crate::tls::{tls_connect_info, TlsAddr, TlsConfig, TlsError, TlsListener};
+
+/// a group of endpoints, each listener serves some of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteSet {
+    Socket,  // `/websocket`, `/longpoll`, `/socket/longpoll`, `/sse/{topic}`
+    Token,   // `/token`
+    Api,     // `/api/...`, broadcast and admin
+    Metrics, // `/metrics`
+    Health,  // `/healthz`, `/readyz`
+}
+
+impl RouteSet {
+    pub const ALL: [RouteSet; 5] = [RouteSet::Socket, RouteSet::Token, RouteSet::Api, RouteSet::Metrics, RouteSet::Health];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RouteSet::Socket => "socket",
+            RouteSet::Token => "token",
+            RouteSet::Api => "api",
+            RouteSet::Metrics => "metrics",
+            RouteSet::Health => "health",
+        }
+    }
+}
+
+impl FromStr for RouteSet {
+    type Err = ListenParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RouteSet::ALL
+            .into_iter()
+            .find(|route_set| route_set.name() == s.trim())
+            .ok_or_else(|| ListenParseError(s.to_string(), "unknown routes, expected socket, token, api, metrics or health"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),   // `host:port`
+    Unix(PathBuf), // `unix:/path`
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(formatter, "{}", addr),
+            ListenAddr::Unix(path) => write!(formatter, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// where to listen and what to serve there
+///
+/// `0.0.0.0:2025?routes=socket,token,health` or `unix:/run/channel/admin.sock?mode=660&routes=api,metrics`;
+/// every route set without `routes`, `mode` is the octal permissions of a Unix socket
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ListenerConfig {
+    pub addr: ListenAddr,
+    pub mode: Option<u32>,     // permissions of the socket file, the umask decides without it
+    pub routes: Vec<RouteSet>, // never empty
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ListenParseError(String, &'static str);
+
+impl Display for ListenParseError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "<ListenParseError: {}, {}>", self.0, self.1)
+    }
+}
+
+impl Error for ListenParseError {}
+
+impl FromStr for ListenerConfig {
+    type Err = ListenParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ListenParseError(s.to_string(), reason);
+        let (addr, query) = s.split_once('?').unwrap_or((s, ""));
+        let addr = match addr.strip_prefix("unix:") {
+            Some("") => return Err(error("missing socket path")),
+            Some(path) => ListenAddr::Unix(PathBuf::from(path)),
+            None if addr
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) =>
+            {
+                ListenAddr::Tcp(addr.to_string())
+            }
+            None => return Err(error("expected host:port or unix:/path")),
+        };
+
+        let mut config = ListenerConfig {
+            addr,
+            mode: None,
+            routes: RouteSet::ALL.to_vec(),
+        };
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            match param.split_once('=') {
+                Some(("mode", mode)) if matches!(config.addr, ListenAddr::Unix(_)) => {
+                    config.mode = Some(
+                        u32::from_str_radix(mode, 8)
+                            .ok()
+                            .filter(|mode| *mode <= 0o777)
+                            .ok_or_else(|| error("invalid mode"))?,
+                    );
+                }
+                Some(("mode", _)) => return Err(error("mode of a TCP listener")),
+                Some(("routes", routes)) => {
+                    let routes = routes.split(',').map(RouteSet::from_str).collect::<Result<Vec<_>, _>>()?;
+                    config.routes = RouteSet::ALL.into_iter().filter(|route_set| routes.contains(route_set)).collect();
+                }
+                _ => return Err(error("unknown parameter, expected mode or routes")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl TryFrom<String> for ListenerConfig {
+    type Error = ListenParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug)]
+pub enum ListenError {
+    Bind(String, std::io::Error),
+    Tls(TlsError),
+}
+
+impl Error for ListenError {}
+
+impl Display for ListenError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenError::Bind(addr, e) => write!(formatter, "<Bind: {}, {}>", addr, e),
+            ListenError::Tls(e) => write!(formatter, "<Tls: {}>", e),
+        }
+    }
+}
+
+/// a bound listener serving its app, until the shutdown future given to `ListenerConfig::bind` completes
+pub type Serving = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+
+/// a stale socket of a previous run is removed, a socket something still listens on and other files fail the bind
+///
+/// with a mode, the socket is bound in a private directory and moved in place once it has its permissions
+fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match std::os::unix::net::UnixStream::connect(path) {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                warn!("LISTEN / removing the stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+            Ok(_) => return Err(std::io::Error::new(ErrorKind::AddrInUse, "the socket is in use")),
+            Err(e) => return Err(e),
+        },
+        Ok(_) => return Err(std::io::Error::new(ErrorKind::AlreadyExists, "not a socket")),
+        Err(_) => {}
+    }
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "not a file path"))?;
+    let private = path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), nanoid::nanoid!(8)));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join(file_name);
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private);
+    result
+}
+
+impl ListenerConfig {
+    /// bind now, so that a taken address fails the startup, TCP listeners serve TLS when it's configured
+    ///
+    /// the socket file of a Unix listener is removed once it's done serving
+    pub async fn bind(
+        &self, app: Router, tls: Option<&TlsConfig>, shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<Serving, ListenError> {
+        let routes = self.routes.iter().map(RouteSet::name).collect::<Vec<_>>().join(",");
+        match (&self.addr, tls) {
+            (ListenAddr::Tcp(addr), Some(tls)) => {
+                let listener = TlsListener::bind(addr, tls).await.map_err(ListenError::Tls)?;
+                info!("LISTEN / serving TLS at {}, routes: {}", addr, routes);
+                let app = app.layer(middleware::from_fn(tls_connect_info));
+                let serve = axum::serve(listener, app.into_make_service_with_connect_info::<TlsAddr>()).with_graceful_shutdown(shutdown);
+                Ok(Box::pin(async move { serve.await }))
+            }
+            (ListenAddr::Tcp(addr), None) => {
+                let listener = TcpListener::bind(addr).await.map_err(|e| ListenError::Bind(addr.clone(), e))?;
+                info!("LISTEN / serving at {}, routes: {}", addr, routes);
+                let serve = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(shutdown);
+                Ok(Box::pin(async move { serve.await }))
+            }
+            (ListenAddr::Unix(path), _) => {
+                let listener = bind_unix(path, self.mode).map_err(|e| ListenError::Bind(self.addr.to_string(), e))?;
+                let mode = self.mode.map_or("umask".into(), |mode| format!("{:o}", mode));
+                info!("LISTEN / serving at {}, mode: {}, routes: {}", self.addr, mode, routes);
+                let serve = axum::serve(listener, app.into_make_service()).with_graceful_shutdown(shutdown);
+                let path = path.clone();
+                Ok(Box::pin(async move {
+                    let result = serve.await;
+                    let _ = std::fs::remove_file(&path);
+                    result
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ChannelServer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn test_listener_config() {
+        let config: ListenerConfig = "127.0.0.1:2025".parse().unwrap();
+        assert_eq!((config.addr, config.routes), (ListenAddr::Tcp("127.0.0.1:2025".into()), RouteSet::ALL.to_vec()));
+        let config: ListenerConfig = "[::1]:2025?routes=socket,token,health".parse().unwrap();
+        assert_eq!(config.routes, vec![RouteSet::Socket, RouteSet::Token, RouteSet::Health]);
+        let config: ListenerConfig = "unix:/run/channel/admin.sock?mode=660&routes=metrics,api".parse().unwrap();
+        assert_eq!(config.addr, ListenAddr::Unix("/run/channel/admin.sock".into()));
+        assert_eq!((config.mode, config.routes), (Some(0o660), vec![RouteSet::Api, RouteSet::Metrics]));
+        assert_eq!(config.addr.to_string(), "unix:/run/channel/admin.sock");
+
+        for (listen, reason) in [
+            ("2025", "expected host:port or unix:/path"),
+            ("localhost:http", "expected host:port or unix:/path"),
+            ("unix:", "missing socket path"),
+            ("unix:/a.sock?mode=999", "invalid mode"),
+            ("127.0.0.1:2025?mode=660", "mode of a TCP listener"),
+            ("127.0.0.1:2025?route=api", "unknown parameter, expected mode or routes"),
+        ] {
+            assert_eq!(listen.parse::<ListenerConfig>(), Err(ListenParseError(listen.into(), reason)));
+        }
+        assert!("127.0.0.1:2025?routes=api,admin"
+            .parse::<ListenerConfig>()
+            .unwrap_err()
+            .to_string()
+            .contains("unknown routes"));
+    }
+
+    async fn get(path: &Path, uri: &str) -> String {
+        let mut stream = UnixStream::connect(path).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n", uri);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_listen_unix() {
+        let server = ChannelServer::builder()
+            .redis_url("redis://127.0.0.1:1")
+            .datetime(false)
+            .build()
+            .await
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("channel-{}.sock", nanoid::nanoid!(8)));
+        let config: ListenerConfig = format!("unix:{}?mode=600&routes=health", path.display()).parse().unwrap();
+
+        // a socket left by a previous run
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = config
+            .bind(server.routes(&config.routes), None, async move {
+                let _ = shutdown_rx.await;
+            })
+            .await
+            .unwrap();
+        let serving = tokio::spawn(serving);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        assert!(get(&path, "/healthz").await.starts_with("HTTP/1.1 200"));
+        assert!(get(&path, "/api/admin/channels").await.starts_with("HTTP/1.1 404"), "not a route of this listener");
+        assert!(get(&path, "/websocket").await.starts_with("HTTP/1.1 404"));
+        assert!(matches!(config.bind(server.router(), None, async {}).await, Err(ListenError::Bind(..))), "in use");
+        assert!(get(&path, "/healthz").await.starts_with("HTTP/1.1 200"), "not removed");
+
+        shutdown_tx.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(!path.exists(), "socket removed");
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(matches!(config.bind(server.router(), None, async {}).await, Err(ListenError::Bind(..))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
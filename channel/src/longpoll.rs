@@ -0,0 +1,349 @@
+use axum::extract::{ConnectInfo, Extension, Json, Query, State as AxumState};
+use axum::http::HeaderMap;
+use redis::aio::MultiplexedConnection;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex, Notify, OnceCell},
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::channel::ChannelMessage;
+use crate::handler::terminate_conn;
+use crate::inbound::ReceiveError;
+use crate::metrics::METRICS;
+use crate::serializer::{Frame, Serializer};
+use crate::tls::ClientIdentity;
+use crate::utils::random_string;
+use crate::websocket::{handle_message, ServerMessage, State};
+
+/// long-poll sessions, the transport phoenix.js falls back to when websockets are blocked
+///
+/// every session is a connection of `ChannelControl`, like a websocket, identified by an opaque session token
+pub struct LongPoll {
+    sessions: Mutex<HashMap<String, Arc<LongPollSession>>>, // session token -> session
+    window: Duration,
+    idle_timeout: Duration,
+}
+
+pub struct LongPollSession {
+    pub token: String,
+    pub conn_id: String,
+    user_token: Option<String>,
+    buffer: Mutex<VecDeque<ServerMessage>>, // outbound messages, drained by GET
+    notify: Notify,
+    last_seen: std::sync::Mutex<Instant>,
+    redis_conn: OnceCell<MultiplexedConnection>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LongPollParams {
+    token: Option<String>,
+
+    #[serde(rename = "userToken")]
+    user_token: Option<String>,
+}
+
+impl Default for LongPoll {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10), Duration::from_secs(20))
+    }
+}
+
+impl LongPoll {
+    /// `window` is how long a GET waits for messages, `idle_timeout` is how long a session lives without any request
+    pub fn new(window: Duration, idle_timeout: Duration) -> Self {
+        LongPoll {
+            sessions: Mutex::new(HashMap::new()),
+            window,
+            idle_timeout,
+        }
+    }
+
+    pub async fn session(&self, token: &str) -> Option<Arc<LongPollSession>> {
+        self.sessions.lock().await.get(token).cloned()
+    }
+
+    pub async fn session_count(&self) -> usize {
+        self.sessions.lock().await.len()
+    }
+
+    async fn remove(&self, token: &str) -> Option<Arc<LongPollSession>> {
+        self.sessions.lock().await.remove(token)
+    }
+}
+
+impl LongPollSession {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    fn last_seen(&self) -> Instant {
+        *self.last_seen.lock().unwrap()
+    }
+
+    async fn push(&self, message: ServerMessage) {
+        self.buffer.lock().await.push_back(message);
+        self.notify.notify_one();
+    }
+
+    async fn drain(&self) -> Vec<String> {
+        self.buffer
+            .lock()
+            .await
+            .drain(..)
+            .filter_map(|message| match Serializer::Json.encode(&message) {
+                Ok(Frame::Text(text)) => {
+                    METRICS.message_out(&message.topic, &message.event);
+                    Some(text)
+                }
+                Ok(Frame::Binary(_)) => None,
+                Err(e) => {
+                    error!("LP / conn: {}, fail to serialize: {}", self.conn_id, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// create a session and its connection, the conn rx => buffer task expires the session when idle
+async fn new_session(state: Arc<State>, user_token: Option<String>, remote_addr: Option<SocketAddr>) -> Arc<LongPollSession> {
+    let conn_id = nanoid::nanoid!(8).to_string();
+    let ctl = state.ctl.lock().await;
+    ctl.conn_add(conn_id.clone(), "longpoll", remote_addr).await;
+    let conn_rx = ctl.conn_rx(conn_id.clone()).await.unwrap();
+    drop(ctl);
+
+    let session = Arc::new(LongPollSession {
+        token: random_string(32),
+        conn_id,
+        user_token,
+        buffer: Mutex::new(VecDeque::new()),
+        notify: Notify::new(),
+        last_seen: std::sync::Mutex::new(Instant::now()),
+        redis_conn: OnceCell::new(),
+    });
+    state.longpoll.sessions.lock().await.insert(session.token.clone(), session.clone());
+    info!("LP / new session, conn: {}", session.conn_id);
+
+    tokio::spawn(session_task(state.clone(), session.clone(), conn_rx));
+    session
+}
+
+async fn session_task(state: Arc<State>, session: Arc<LongPollSession>, mut conn_rx: tokio::sync::broadcast::Receiver<ChannelMessage>) {
+    let idle_timeout = state.longpoll.idle_timeout;
+    loop {
+        tokio::select! {
+            message = conn_rx.recv() => match message {
+                Ok(message) => match message.into_reply() {
+                    Some(reply) => session.push(reply).await,
+                    None => break, // close
+                },
+                Err(RecvError::Lagged(n)) => {
+                    warn!("LP / conn: {}, lagged, {} messages dropped", session.conn_id, n);
+                    METRICS.lagged("longpoll", n);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = tokio::time::sleep_until(session.last_seen() + idle_timeout) => {
+                if session.last_seen() + idle_timeout <= Instant::now() {
+                    info!("LP / conn: {}, idle for {:?}, expired", session.conn_id, idle_timeout);
+                    break;
+                }
+            }
+        }
+    }
+
+    state.longpoll.remove(&session.token).await;
+    terminate_conn(&state, &session.conn_id, "closed").await;
+    state.ctl.lock().await.conn_cleanup(session.conn_id.clone()).await;
+    info!("LP / session closed, conn: {}", session.conn_id);
+}
+
+/// GET: create a session (status 410 with a new token), or wait for buffered messages (200) until the window ends (204)
+pub async fn longpoll_get(
+    remote: Option<Extension<ConnectInfo<SocketAddr>>>, client_identity: Option<Extension<ClientIdentity>>, Query(params): Query<LongPollParams>,
+    headers: HeaderMap, AxumState(state): AxumState<Arc<State>>,
+) -> Json<serde_json::Value> {
+    let session = match &params.token {
+        Some(token) => state.longpoll.session(token).await,
+        None => None,
+    };
+    let Some(session) = session else {
+        if state.draining.load(Ordering::SeqCst) {
+            return Json(json!({"status": 503}));
+        }
+        if state.origins.rejected(&headers, "longpoll").is_some() {
+            return Json(json!({"status": 403}));
+        }
+        // phoenix.js opens the transport on 410 and polls again with the token
+        let remote_addr = remote.map(|Extension(ConnectInfo(remote_addr))| remote_addr);
+        let session = new_session(state.clone(), params.user_token.clone(), remote_addr).await;
+        if let Some(Extension(client_identity)) = client_identity {
+            state.ctl.lock().await.conn_set_identity(&session.conn_id, client_identity).await;
+        }
+        return Json(json!({"status": 410, "token": session.token}));
+    };
+
+    session.touch();
+    if session.buffer.lock().await.is_empty() {
+        let _ = tokio::time::timeout(state.longpoll.window, session.notify.notified()).await;
+    }
+    session.touch();
+
+    let messages = session.drain().await;
+    if messages.is_empty() {
+        return Json(json!({"status": 204, "token": session.token}));
+    }
+    debug!("LP / conn: {}, {} messages polled", session.conn_id, messages.len());
+    Json(json!({"status": 200, "token": session.token, "messages": messages}))
+}
+
+/// POST: a batch of messages, one JSON array per line, handled like websocket frames
+pub async fn longpoll_post(Query(params): Query<LongPollParams>, AxumState(state): AxumState<Arc<State>>, body: String) -> Json<serde_json::Value> {
+    let session = match &params.token {
+        Some(token) => state.longpoll.session(token).await,
+        None => None,
+    };
+    let Some(session) = session else {
+        return Json(json!({"status": 410}));
+    };
+    session.touch();
+
+    let lines = body.lines().filter(|line| !line.trim().is_empty()).collect::<Vec<_>>();
+    let max_frame_size = state.message_limits.read().unwrap().max_frame_size;
+    if lines.iter().any(|line| line.len() > max_frame_size) {
+        warn!("LP / conn: {}, message over {} bytes", session.conn_id, max_frame_size);
+        METRICS.limit_exceeded.with_label_values(&["frame"]).inc();
+        return Json(json!({"status": 413}));
+    }
+
+    let redis_client = state.redis_client.clone();
+    let redis_conn = session
+        .redis_conn
+        .get_or_try_init(|| async move { redis_client.get_multiplexed_async_connection().await })
+        .await;
+    let redis_conn = match redis_conn {
+        Ok(redis_conn) => redis_conn.clone(),
+        Err(e) => {
+            ReceiveError::Redis(e).report(&session.conn_id);
+            return Json(json!({"status": 500}));
+        }
+    };
+
+    for line in lines {
+        let frame = Frame::Text(line.to_string());
+        handle_message(state.clone(), session.user_token.clone(), &session.conn_id, Serializer::Json, &frame, Some(redis_conn.clone())).await;
+    }
+    Json(json!({"status": 200}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::ServerPayload;
+
+    fn test_state(longpoll: LongPoll) -> Arc<State> {
+        let redis_client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+        Arc::new(State {
+            longpoll,
+            ..State::new(redis_client, "secret".into())
+        })
+    }
+
+    fn params(token: Option<&str>) -> Query<LongPollParams> {
+        Query(LongPollParams {
+            token: token.map(String::from),
+            user_token: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_longpoll_session_and_messages() {
+        let state = test_state(LongPoll::new(Duration::from_millis(100), Duration::from_secs(5)));
+
+        let Json(resp) = longpoll_get(None, None, params(None), HeaderMap::new(), AxumState(state.clone())).await;
+        assert_eq!(resp["status"], 410);
+        let token = resp["token"].as_str().unwrap().to_string();
+        assert_eq!(state.longpoll.session_count().await, 1);
+
+        // nothing buffered, the window passes
+        let Json(resp) = longpoll_get(None, None, params(Some(&token)), HeaderMap::new(), AxumState(state.clone())).await;
+        assert_eq!(resp["status"], 204);
+        assert_eq!(resp["token"], token);
+
+        let conn_id = state.longpoll.session(&token).await.unwrap().conn_id.clone();
+        let message = ServerMessage {
+            join_ref: None,
+            event_ref: "1".into(),
+            topic: "system".into(),
+            event: "hello".into(),
+            payload: ServerPayload::ServerJsonValue(json!({"n": 1})),
+        };
+        state.ctl.lock().await.conn_send(conn_id, ChannelMessage::Reply(message)).await.unwrap();
+
+        let Json(resp) = longpoll_get(None, None, params(Some(&token)), HeaderMap::new(), AxumState(state.clone())).await;
+        assert_eq!(resp["status"], 200);
+        let messages = resp["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        let message: serde_json::Value = serde_json::from_str(messages[0].as_str().unwrap()).unwrap();
+        assert_eq!(message, json!([null, "1", "system", "hello", {"n": 1}]));
+    }
+
+    #[tokio::test]
+    async fn test_longpoll_frame_too_big() {
+        let state = test_state(LongPoll::default());
+        let Json(resp) = longpoll_get(None, None, params(None), HeaderMap::new(), AxumState(state.clone())).await;
+        let token = resp["token"].as_str().unwrap().to_string();
+
+        let max_frame_size = state.message_limits.read().unwrap().max_frame_size;
+        let line = format!(r#"["1", "2", "room", "shout", {{"text": "{}"}}]"#, "a".repeat(max_frame_size));
+        let Json(resp) = longpoll_post(params(Some(&token)), AxumState(state.clone()), line).await;
+        assert_eq!(resp["status"], 413);
+    }
+
+    #[tokio::test]
+    async fn test_longpoll_unknown_token() {
+        let state = test_state(LongPoll::default());
+
+        let Json(resp) = longpoll_post(params(Some("missing")), AxumState(state.clone()), "".into()).await;
+        assert_eq!(resp["status"], 410);
+
+        // a GET with an unknown token starts over with a new session
+        let Json(resp) = longpoll_get(None, None, params(Some("missing")), HeaderMap::new(), AxumState(state.clone())).await;
+        assert_eq!(resp["status"], 410);
+        assert_ne!(resp["token"], "missing");
+    }
+
+    #[tokio::test]
+    async fn test_longpoll_session_expires() {
+        let state = test_state(LongPoll::new(Duration::from_millis(10), Duration::from_millis(50)));
+
+        let Json(resp) = longpoll_get(None, None, params(None), HeaderMap::new(), AxumState(state.clone())).await;
+        let token = resp["token"].as_str().unwrap().to_string();
+        let conn_id = state.longpoll.session(&token).await.unwrap().conn_id.clone();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(state.longpoll.session(&token).await.is_none());
+        let sent = state.ctl.lock().await.conn_send(conn_id, ChannelMessage::Reply(empty_message())).await;
+        assert!(sent.is_err(), "connection should be cleaned up");
+    }
+
+    fn empty_message() -> ServerMessage {
+        ServerMessage {
+            join_ref: None,
+            event_ref: "0".into(),
+            topic: "phoenix".into(),
+            event: "phx_reply".into(),
+            payload: ServerPayload::ServerJsonValue(json!({})),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use axum::http::header;
+use axum::response::IntoResponse;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::sync::{LazyLock, RwLock};
+use std::time::Instant;
+
+use crate::channel::ChannelError;
+use crate::utils::topic_matches;
+
+/// process wide metrics, exported by `GET /metrics` in the Prometheus text format
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub connections: IntGauge,
+    pub agents: IntGaugeVec,         // channel, the topic pattern
+    pub messages_in: IntCounterVec,  // channel, the topic pattern, and event; pushed by clients
+    pub messages_out: IntCounterVec, // channel, the topic pattern, and event; sent to clients
+    pub redis_publish_failures: IntCounter,
+    pub lagged: IntCounterVec,           // stage; messages dropped by a lagging broadcast receiver
+    pub join_failures: IntCounterVec,    // reason
+    pub join_duration: Histogram,        // seconds
+    pub delivery_delay: HistogramVec,    // transport; seconds from redis receipt to sending to the client
+    pub rate_limited: IntCounterVec,     // limit; messages rejected by the rate limits
+    pub limit_exceeded: IntCounterVec,   // limit; frames and messages over the size and join limits
+    pub receive_errors: IntCounterVec,   // reason; failures of the receive path
+    pub origin_rejected: IntCounterVec,  // transport; websocket upgrades, long-poll sessions and SSE subscriptions from origins that aren't allowed
+    topic_patterns: RwLock<Vec<String>>, // the channel labels, `other` for topics matching none
+    events: RwLock<Vec<String>>,         // the event labels besides `EVENTS`, `other` for the rest
+}
+
+/// events of the protocol, always labeled by name
+const EVENTS: [&str; 10] = [
+    "phx_join",
+    "phx_leave",
+    "phx_refresh",
+    "phx_reply",
+    "phx_close",
+    "phx_error",
+    "heartbeat",
+    "presence_state",
+    "presence_diff",
+    "token_expiring",
+];
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("channel".into()), None).unwrap();
+        let metrics = Metrics {
+            connections: IntGauge::new("connections", "active websocket and long-poll connections").unwrap(),
+            agents: IntGaugeVec::new(Opts::new("agents", "agents joined per channel"), &["channel"]).unwrap(),
+            messages_in: IntCounterVec::new(Opts::new("messages_in_total", "messages pushed by clients"), &["channel", "event"]).unwrap(),
+            messages_out: IntCounterVec::new(Opts::new("messages_out_total", "messages sent to clients"), &["channel", "event"]).unwrap(),
+            redis_publish_failures: IntCounter::new("redis_publish_failures_total", "failed publishes to redis").unwrap(),
+            lagged: IntCounterVec::new(Opts::new("lagged_messages_total", "messages dropped by lagging receivers"), &["stage"]).unwrap(),
+            join_failures: IntCounterVec::new(Opts::new("join_failures_total", "failed phx_join by reason"), &["reason"]).unwrap(),
+            join_duration: Histogram::with_opts(
+                HistogramOpts::new("join_duration_seconds", "phx_join handling time")
+                    .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            )
+            .unwrap(),
+            delivery_delay: HistogramVec::new(
+                HistogramOpts::new("delivery_delay_seconds", "delay from redis receipt to sending to the client")
+                    .buckets(vec![0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0]),
+                &["transport"],
+            )
+            .unwrap(),
+            rate_limited: IntCounterVec::new(Opts::new("rate_limited_total", "messages rejected by the rate limits"), &["limit"]).unwrap(),
+            limit_exceeded: IntCounterVec::new(Opts::new("limit_exceeded_total", "frames and messages over the size and join limits"), &["limit"])
+                .unwrap(),
+            receive_errors: IntCounterVec::new(Opts::new("receive_errors_total", "failures of the receive path"), &["reason"]).unwrap(),
+            origin_rejected: IntCounterVec::new(Opts::new("origin_rejected_total", "requests from origins that aren't allowed"), &["transport"])
+                .unwrap(),
+            topic_patterns: RwLock::new(vec![]),
+            events: RwLock::new(vec![]),
+            registry,
+        };
+
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.connections.clone())).unwrap();
+        registry.register(Box::new(metrics.agents.clone())).unwrap();
+        registry.register(Box::new(metrics.messages_in.clone())).unwrap();
+        registry.register(Box::new(metrics.messages_out.clone())).unwrap();
+        registry.register(Box::new(metrics.redis_publish_failures.clone())).unwrap();
+        registry.register(Box::new(metrics.lagged.clone())).unwrap();
+        registry.register(Box::new(metrics.join_failures.clone())).unwrap();
+        registry.register(Box::new(metrics.join_duration.clone())).unwrap();
+        registry.register(Box::new(metrics.delivery_delay.clone())).unwrap();
+        registry.register(Box::new(metrics.rate_limited.clone())).unwrap();
+        registry.register(Box::new(metrics.limit_exceeded.clone())).unwrap();
+        registry.register(Box::new(metrics.receive_errors.clone())).unwrap();
+        registry.register(Box::new(metrics.origin_rejected.clone())).unwrap();
+        metrics
+    }
+
+    /// the patterns of the topics the message counters are labeled by, so that clients can't add labels
+    pub fn set_topic_patterns(&self, patterns: Vec<String>) {
+        *self.topic_patterns.write().unwrap() = patterns;
+    }
+
+    /// the events the message counters are labeled by besides the protocol's, e.g. the events of the policies
+    pub fn set_events(&self, events: Vec<String>) {
+        *self.events.write().unwrap() = events;
+    }
+
+    /// the first pattern matching the topic, `other` without any
+    pub fn topic_label(&self, topic: &str) -> String {
+        let patterns = self.topic_patterns.read().unwrap();
+        patterns
+            .iter()
+            .find(|pattern| topic_matches(pattern, topic))
+            .map_or_else(|| "other".into(), |pattern| pattern.clone())
+    }
+
+    /// the event if it's the protocol's or set by `set_events`, `other` otherwise
+    fn event_label<'a>(&self, event: &'a str) -> &'a str {
+        match EVENTS.contains(&event) || self.events.read().unwrap().iter().any(|known| known == event) {
+            true => event,
+            false => "other",
+        }
+    }
+
+    pub fn message_in(&self, topic: &str, event: &str) {
+        self.messages_in
+            .with_label_values(&[&self.topic_label(topic), self.event_label(event)])
+            .inc();
+    }
+
+    pub fn message_out(&self, topic: &str, event: &str) {
+        self.messages_out
+            .with_label_values(&[&self.topic_label(topic), self.event_label(event)])
+            .inc();
+    }
+
+    pub fn lagged(&self, stage: &str, count: u64) {
+        self.lagged.with_label_values(&[stage]).inc_by(count);
+    }
+
+    pub fn join_failure(&self, e: &ChannelError) {
+        let reason = match e {
+            ChannelError::ChannelNotFound => "channel_not_found",
+            ChannelError::ChannelEmpty => "channel_empty",
+            ChannelError::MessageSendError => "message_send_error",
+            ChannelError::AgentNotInitiated => "agent_not_initiated",
+            ChannelError::BadToken => "bad_token",
+            ChannelError::JoinRejected => "rejected",
+            ChannelError::TokenRevoked => "token_revoked",
+            ChannelError::Unauthorized => "unauthorized",
+        };
+        self.join_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// `received_at` is when the message came from redis, if it did
+    pub fn delivered(&self, transport: &str, received_at: Option<Instant>) {
+        if let Some(received_at) = received_at {
+            self.delivery_delay
+                .with_label_values(&[transport])
+                .observe(received_at.elapsed().as_secs_f64());
+        }
+    }
+
+    /// `label` is the `topic_label` of the channel, taken when it's created so that joins and leaves count on the same one
+    pub fn agent_joined(&self, label: &str) {
+        self.agents.with_label_values(&[label]).inc();
+    }
+
+    pub fn agents_left(&self, label: &str, count: usize) {
+        self.agents.with_label_values(&[label]).sub(count as i64);
+    }
+
+    /// the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// `GET /metrics`
+pub async fn metrics_handler() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], METRICS.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_render() {
+        let metrics = Metrics::new();
+        metrics.connections.inc();
+        metrics.set_topic_patterns(vec!["room:*".into()]);
+        metrics.set_events(vec!["shout".into()]);
+        for _ in 0..3 {
+            metrics.agent_joined(&metrics.topic_label("room:1"));
+        }
+        metrics.agents_left("room:*", 1);
+        metrics.message_in("room:1", "shout");
+        metrics.message_in("room:2", "shout");
+        metrics.message_in("room:2", "whatever");
+        metrics.message_out("lobby", "presence_diff");
+        metrics.lagged("agent", 3);
+        metrics.join_failure(&ChannelError::BadToken);
+        metrics.join_duration.observe(0.002);
+        metrics.delivered("websocket", Some(Instant::now()));
+        metrics.delivered("websocket", None);
+
+        let text = metrics.render();
+        assert!(text.contains("channel_connections 1"));
+        assert!(text.contains(r#"channel_agents{channel="room:*"} 2"#));
+        assert!(text.contains(r#"channel_messages_in_total{channel="room:*",event="shout"} 2"#));
+        assert!(text.contains(r#"channel_messages_in_total{channel="room:*",event="other"} 1"#));
+        assert!(text.contains(r#"channel_messages_out_total{channel="other",event="presence_diff"} 1"#));
+        assert!(text.contains(r#"channel_lagged_messages_total{stage="agent"} 3"#));
+        assert!(text.contains(r#"channel_join_failures_total{reason="bad_token"} 1"#));
+        assert!(text.contains("channel_join_duration_seconds_count 1"));
+        assert!(text.contains(r#"channel_delivery_delay_seconds_count{transport="websocket"} 1"#));
+    }
+}
@@ -0,0 +1,113 @@
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
+
+use crate::metrics::METRICS;
+
+/// allowed `Origin`s of the websocket upgrades, long-poll sessions, SSE subscriptions and cross-origin HTTP requests
+///
+/// `https://app.example.com` matches exactly, `https://*.example.com` any subdomain, `*` any origin;
+/// every origin is allowed when there's no pattern, requests without `Origin` aren't from a browser and always are
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Origins {
+    patterns: Vec<String>,
+}
+
+impl Origins {
+    pub fn new(patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Origins {
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| pattern.into().trim_end_matches('/').to_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        self.patterns.is_empty() || self.patterns.iter().any(|pattern| origin_matches(pattern, &origin))
+    }
+
+    /// the `Origin` of a request that isn't allowed, logged and counted by `channel_origin_rejected_total{transport}`
+    pub fn rejected<'a>(&self, headers: &'a HeaderMap, transport: &str) -> Option<&'a str> {
+        let origin = headers.get(header::ORIGIN)?;
+        let origin = origin.to_str().unwrap_or("<invalid>");
+        if self.allows(origin) {
+            return None;
+        }
+        warn!("ORIGIN / {} rejected, origin: {}", transport, origin);
+        METRICS.origin_rejected.with_label_values(&[transport]).inc();
+        Some(origin)
+    }
+
+    /// CORS of the HTTP routes, `None` without patterns, browsers keep the same-origin policy then
+    pub fn cors_layer(&self) -> Option<CorsLayer> {
+        if self.patterns.is_empty() {
+            return None;
+        }
+        let origins = self.clone();
+        let allow_origin = AllowOrigin::predicate(move |origin: &HeaderValue, _| origin.to_str().is_ok_and(|origin| origins.allows(origin)));
+        Some(
+            CorsLayer::new()
+                .allow_origin(allow_origin)
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers([
+                    header::AUTHORIZATION,
+                    header::CONTENT_TYPE,
+                    header::HeaderName::from_static("last-event-id"),
+                ])
+                .max_age(Duration::from_secs(3600)),
+        )
+    }
+}
+
+/// `scheme://host[:port]` against a pattern, `*.` in front of the host matches any subdomain, not the domain itself
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern == origin {
+        return true;
+    }
+    let (Some((pattern_scheme, pattern_host)), Some((scheme, host))) = (pattern.split_once("://"), origin.split_once("://")) else {
+        return false;
+    };
+    match pattern_host.strip_prefix("*.") {
+        Some(domain) => {
+            pattern_scheme == scheme
+                && host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.'))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_matches() {
+        let origins = Origins::new(["https://app.example.com", "https://*.example.org/", "http://localhost:3000"]);
+        assert!(origins.allows("https://app.example.com"));
+        assert!(origins.allows("HTTPS://App.Example.com"));
+        assert!(!origins.allows("http://app.example.com"), "another scheme");
+        assert!(origins.allows("https://a.example.org") && origins.allows("https://a.b.example.org"));
+        assert!(!origins.allows("https://example.org"), "the domain isn't a subdomain");
+        assert!(!origins.allows("https://evilexample.org"));
+        assert!(!origins.allows("https://a.example.org:8443"), "another port");
+        assert!(origins.allows("http://localhost:3000") && !origins.allows("http://localhost:3001"));
+        assert!(Origins::default().allows("https://anything.test"));
+        assert!(Origins::new(["*"]).allows("https://anything.test"));
+
+        let rejected = METRICS.origin_rejected.with_label_values(&["test"]).get();
+        let mut headers = HeaderMap::new();
+        assert_eq!(origins.rejected(&headers, "test"), None, "not a browser");
+        headers.insert(header::ORIGIN, HeaderValue::from_static("https://evil.test"));
+        assert_eq!(origins.rejected(&headers, "test"), Some("https://evil.test"));
+        assert_eq!(METRICS.origin_rejected.with_label_values(&["test"]).get() - rejected, 1);
+    }
+}
@@ -0,0 +1,419 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::channel::{ChannelMessage, CLOSE_POLICY_VIOLATION};
+use crate::inbound::{Context, InboundMiddleware, Next};
+use crate::metrics::METRICS;
+use crate::utils::topic_matches;
+use crate::websocket::{reply, RequestMessage, RequestPayload};
+
+/// buckets and strikes untouched for this long are forgotten
+const IDLE: Duration = Duration::from_secs(60);
+
+/// a token bucket, `burst` messages at once, refilled with `per_sec` messages per second
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_sec: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LimitParseError(String);
+
+impl Display for LimitParseError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "<LimitParseError: {}, expected burst/per_sec, e.g. 20/5>", self.0)
+    }
+}
+
+impl std::error::Error for LimitParseError {}
+
+/// `20/5`, a burst of 20 messages, 5 per second
+impl FromStr for Limit {
+    type Err = LimitParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, per_sec) = s.split_once('/').ok_or_else(|| LimitParseError(s.to_string()))?;
+        let limit = Limit {
+            burst: burst.trim().parse().map_err(|_| LimitParseError(s.to_string()))?,
+            per_sec: per_sec.trim().parse().map_err(|_| LimitParseError(s.to_string()))?,
+        };
+        match limit.burst > 0 && limit.per_sec >= 0.0 {
+            true => Ok(limit),
+            false => Err(LimitParseError(s.to_string())),
+        }
+    }
+}
+
+/// limits of a topic, `None` is unlimited
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Limits {
+    pub join: Option<Limit>, // phx_join per connection
+    pub push: Option<Limit>, // pushes per connection, topic and event
+    pub user: Option<Limit>, // messages per external id and topic pattern, across the connections of this node, each node counts its own
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    pub default: Limits,
+    pub topics: Vec<(String, Limits)>, // overrides by topic pattern, the first match wins
+    pub disconnect_after: u32,         // consecutive rate limited messages before the connection is closed, 0 never
+}
+
+impl RateLimits {
+    /// the limits of a topic and the pattern they come from, buckets are kept per pattern
+    fn for_topic(&self, topic: &str) -> (&Limits, &str) {
+        self.topics
+            .iter()
+            .find(|(pattern, _)| topic_matches(pattern, topic))
+            .map_or((&self.default, ""), |(pattern, limits)| (limits, pattern.as_str()))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: &Limit) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst as f64);
+        self.updated_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    buckets: HashMap<String, Bucket>,
+    strikes: HashMap<String, (u32, Instant)>, // conn_id -> consecutive rate limited messages
+    pruned_at: Option<Instant>,
+}
+
+impl Counters {
+    fn take(&mut self, key: String, limit: &Limit) -> bool {
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| Bucket {
+                tokens: limit.burst as f64,
+                updated_at: Instant::now(),
+            })
+            .take(limit)
+    }
+
+    /// buckets idle for `IDLE` are full again, as good as new ones
+    fn prune(&mut self) {
+        let now = Instant::now();
+        if self.pruned_at.is_some_and(|pruned_at| now.duration_since(pruned_at) < IDLE) {
+            return;
+        }
+        self.buckets.retain(|_, bucket| now.duration_since(bucket.updated_at) < IDLE);
+        self.strikes.retain(|_, (_, at)| now.duration_since(*at) < IDLE);
+        self.pruned_at = Some(now);
+    }
+}
+
+/// token-bucket rate limits on joins, pushes and users, an inbound middleware
+///
+/// a limited message gets `{"status": "error", "response": {"reason": "rate_limited"}}`,
+/// after `disconnect_after` of them in a row the connection is closed with 1008 (policy violation)
+pub struct RateLimit {
+    limits: RwLock<RateLimits>,
+    counters: Mutex<Counters>,
+}
+
+impl RateLimit {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimit {
+            limits: RwLock::new(limits),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits.read().unwrap().clone()
+    }
+
+    /// new limits apply to the next messages, the buckets are kept
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// the external id of the sender: the agent the message is for, the first agent joined on the connection,
+    /// the join token, or the connection's own token or client certificate; tokens are verified only before a join
+    async fn external_id(&self, rm: &RequestMessage, ctx: &Context) -> Option<String> {
+        {
+            let agent_id = format!("{}:{}:{}", ctx.conn_id, rm.topic, rm.join_ref.clone().unwrap_or_default());
+            let ctl = ctx.state.ctl.lock().await;
+            if let Some(agent) = ctl.agents.lock().await.get(&agent_id) {
+                return Some(agent.external_id.clone());
+            }
+            if let Some(external_id) = ctl.conn_external_id(&ctx.conn_id).await {
+                return Some(external_id);
+            }
+        }
+        if let ("phx_join", RequestPayload::Join { token, .. }) = (rm.event.as_str(), &rm.payload) {
+            return ctx.state.jwt.decode(token).await.ok().map(|claims| claims.id);
+        }
+        match &ctx.user_token {
+            Some(token) => ctx.state.jwt.decode(token).await.ok().map(|claims| claims.id),
+            None => ctx
+                .state
+                .ctl
+                .lock()
+                .await
+                .conn_identity(&ctx.conn_id)
+                .await
+                .map(|identity| identity.claims().id),
+        }
+    }
+
+    /// the limit exceeded by a message, if any; the connection's buckets come first, the user is found only if they pass
+    async fn exceeded(&self, rm: &RequestMessage, ctx: &Context) -> Option<&'static str> {
+        let (limits, pattern) = {
+            let rate_limits = self.limits.read().unwrap();
+            let (limits, pattern) = rate_limits.for_topic(&rm.topic);
+            (limits.clone(), pattern.to_string())
+        };
+
+        let (name, limit, key) = match rm.event.as_str() {
+            "phx_join" => ("join", limits.join, format!("join:{}:{}", pattern, ctx.conn_id)),
+            "phx_leave" => ("push", None, String::new()),
+            event => ("push", limits.push, format!("push:{}:{}:{}", ctx.conn_id, rm.topic, event)),
+        };
+        {
+            let mut counters = self.counters.lock().await;
+            counters.prune();
+            if limit.is_some_and(|limit| !counters.take(key, &limit)) {
+                return Some(name);
+            }
+        }
+
+        // one bucket per user and topic pattern, the default limits have their own
+        let limit = limits.user?;
+        let external_id = self.external_id(rm, ctx).await?;
+        match self.counters.lock().await.take(format!("user:{}:{}", pattern, external_id), &limit) {
+            true => None,
+            false => Some("user"),
+        }
+    }
+}
+
+#[async_trait]
+impl InboundMiddleware for RateLimit {
+    async fn call(&self, rm: RequestMessage, ctx: &mut Context, next: Next<'_>) -> Result<(), serde_json::Value> {
+        // heartbeats keep the connection alive, they're never limited
+        if rm.topic == "phoenix" && rm.event == "heartbeat" {
+            return next.run(rm, ctx).await;
+        }
+
+        let Some(name) = self.exceeded(&rm, ctx).await else {
+            self.counters.lock().await.strikes.remove(&ctx.conn_id);
+            return next.run(rm, ctx).await;
+        };
+        METRICS.rate_limited.with_label_values(&[name]).inc();
+        let reason = json!({"reason": "rate_limited"});
+
+        let strikes = {
+            let mut counters = self.counters.lock().await;
+            let (strikes, at) = counters.strikes.entry(ctx.conn_id.clone()).or_insert((0, Instant::now()));
+            *strikes += 1;
+            *at = Instant::now();
+            *strikes
+        };
+        let disconnect_after = self.limits.read().unwrap().disconnect_after;
+        if disconnect_after == 0 || strikes < disconnect_after {
+            return Err(reason);
+        }
+
+        // the reply goes out before the close
+        warn!("RATE_LIMIT / conn: {}, {} rate limited messages in a row, disconnecting", ctx.conn_id, strikes);
+        self.counters.lock().await.strikes.remove(&ctx.conn_id);
+        reply(&ctx.conn_id, rm.join_ref, &rm.event_ref, &rm.topic, "error", reason, ctx.state.clone()).await;
+        let close = ChannelMessage::Close {
+            code: CLOSE_POLICY_VIOLATION,
+            reason: "rate limited".into(),
+        };
+        let _ = ctx.state.ctl.lock().await.conn_send(ctx.conn_id.clone(), close).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::Pipeline;
+    use crate::utils::Claims;
+    use crate::websocket::{ServerPayload, State};
+    use std::sync::Arc;
+
+    /// stands for the built-in steps, nothing is published
+    struct Accept;
+
+    #[async_trait]
+    impl InboundMiddleware for Accept {
+        async fn call(&self, _rm: RequestMessage, _ctx: &mut Context, _next: Next<'_>) -> Result<(), serde_json::Value> {
+            Ok(())
+        }
+    }
+
+    fn request(topic: &str, event: &str, payload: serde_json::Value) -> RequestMessage {
+        serde_json::from_value(json!(["1", "2", topic, event, payload])).unwrap()
+    }
+
+    #[test]
+    fn test_rate_limit_parse() {
+        assert_eq!("20/5".parse(), Ok(Limit { burst: 20, per_sec: 5.0 }));
+        assert_eq!(" 1 / 0.5 ".parse(), Ok(Limit { burst: 1, per_sec: 0.5 }));
+        assert!("20".parse::<Limit>().is_err());
+        assert!("0/5".parse::<Limit>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_pipeline() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.conn_add("c1".into(), "websocket", None).await;
+            ctl.channel_add("room".into(), None).await;
+            ctl.agent_add("c1:room:1".into(), None).await;
+            ctl.channel_join_with_meta("room", "c1:room:1".into(), "alice".into(), json!({}))
+                .await
+                .unwrap();
+        }
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+
+        let rate_limited = METRICS.rate_limited.with_label_values(&["push"]).get();
+        let once = Some(Limit { burst: 1, per_sec: 0.0 });
+        let mut pipeline = Pipeline::default();
+        pipeline.layer(Arc::new(RateLimit::new(RateLimits {
+            default: Limits {
+                join: once,
+                push: once,
+                user: Some(Limit { burst: 3, per_sec: 0.0 }),
+            },
+            topics: vec![("vip:*".into(), Limits::default())],
+            disconnect_after: 2,
+        })));
+        pipeline.layer(Arc::new(Accept));
+        let mut ctx = Context::new(state.clone(), "c1".into(), None, None);
+        let mut run = async |rm: RequestMessage| {
+            pipeline.run(rm, &mut ctx).await;
+            conn_rx.try_recv().ok()
+        };
+
+        let token = state
+            .jwt
+            .sign(
+                Claims {
+                    id: "alice".into(),
+                    channel: "room".into(),
+                    ..Claims::default()
+                },
+                60,
+            )
+            .await
+            .unwrap();
+        assert!(run(request("room", "phx_join", json!({"token": token}))).await.is_none());
+        for _ in 0..3 {
+            assert!(run(request("vip:1", "phx_join", json!({"token": token}))).await.is_none(), "not limited");
+        }
+        assert!(run(request("phoenix", "heartbeat", json!({}))).await.is_none());
+        assert!(run(request("room", "shout", json!({}))).await.is_none());
+        assert!(run(request("room", "whisper", json!({}))).await.is_none(), "per event");
+
+        let another_join_ref = serde_json::from_value(json!(["9", "2", "room", "shout", {}])).unwrap();
+        let Some(ChannelMessage::Reply(limited)) = run(another_join_ref).await else {
+            panic!("rate limited, whatever the join_ref")
+        };
+        let ServerPayload::ServerJsonValue(payload) = limited.payload else {
+            panic!("json reply")
+        };
+        assert_eq!(payload, json!({"status": "error", "response": {"reason": "rate_limited"}}));
+
+        run(request("room", "whisper", json!({}))).await;
+        let Ok(ChannelMessage::Close { code, .. }) = conn_rx.try_recv() else {
+            panic!("disconnected after 2 in a row")
+        };
+        assert_eq!(code, CLOSE_POLICY_VIOLATION);
+        assert_eq!(METRICS.rate_limited.with_label_values(&["push"]).get() - rate_limited, 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_connection_user() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        state.ctl.lock().await.conn_add("c1".into(), "websocket", None).await;
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+
+        let rate_limited = METRICS.rate_limited.with_label_values(&["user"]).get();
+        let mut pipeline = Pipeline::default();
+        pipeline.layer(Arc::new(RateLimit::new(RateLimits {
+            default: Limits {
+                user: Some(Limit { burst: 1, per_sec: 0.0 }),
+                ..Limits::default()
+            },
+            ..RateLimits::default()
+        })));
+        pipeline.layer(Arc::new(Accept));
+        let token = state
+            .jwt
+            .sign(
+                Claims {
+                    id: "alice".into(),
+                    channel: "lobby".into(),
+                    ..Claims::default()
+                },
+                60,
+            )
+            .await
+            .unwrap();
+        let mut ctx = Context::new(state.clone(), "c1".into(), Some(token), None);
+
+        // nothing joined, the user is the one of the connection's token
+        pipeline.run(request("lobby", "shout", json!({})), &mut ctx).await;
+        assert!(conn_rx.try_recv().is_err());
+        pipeline.run(request("lobby", "wave", json!({})), &mut ctx).await;
+        assert!(matches!(conn_rx.try_recv(), Ok(ChannelMessage::Reply(_))), "rate limited");
+        assert_eq!(METRICS.rate_limited.with_label_values(&["user"]).get() - rate_limited, 1);
+
+        // once an agent joined, the connection's user is the one of its token
+        let ctl = state.ctl.lock().await;
+        ctl.conn_add("c2".into(), "websocket", None).await;
+        ctl.channel_add("room".into(), None).await;
+        for (agent_id, id) in [("c2:room:1", "bob"), ("c2:room:2", "carol")] {
+            ctl.agent_add(agent_id.into(), None).await;
+            ctl.channel_join("room", agent_id.into(), id.into()).await.unwrap();
+            let claims = Claims {
+                id: id.into(),
+                ..Claims::default()
+            };
+            ctl.agent_set_claims(agent_id, claims).await;
+        }
+        assert_eq!(ctl.conn_external_id("c2").await.as_deref(), Some("bob"));
+        let mut conn_rx = ctl.conn_rx("c2".into()).await.unwrap();
+        drop(ctl);
+        let mut ctx = Context::new(state.clone(), "c2".into(), None, None);
+        pipeline.run(request("lobby", "phx_join", json!({"token": "garbage"})), &mut ctx).await;
+        assert!(conn_rx.try_recv().is_err(), "bob's first message");
+        pipeline.run(request("lobby", "phx_join", json!({"token": "garbage"})), &mut ctx).await;
+        assert!(matches!(conn_rx.try_recv(), Ok(ChannelMessage::Reply(_))), "bob rate limited, the token isn't verified");
+    }
+}
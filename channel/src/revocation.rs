@@ -0,0 +1,293 @@
+use futures::StreamExt;
+use redis::{aio::MultiplexedConnection, AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc};
+use tracing::{error, info, warn};
+
+use crate::channel::{ChannelMessage, CLOSE_POLICY_VIOLATION};
+use crate::handler::{terminate, terminate_conn};
+use crate::utils::Claims;
+use crate::websocket::State;
+
+/// redis channel of revocations, every node disconnects the revoked agents it has
+pub const REVOCATION_TOPIC: &str = "node:revoke";
+
+/// a revoked token, or the tokens of a user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Revocation {
+    /// until `exp`, for the longest token lifetime if missing
+    Token {
+        jti: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exp: Option<usize>,
+    },
+    /// the tokens of the user issued at or before `at`, now if missing; tokens without `iat` are revoked too
+    User {
+        user: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at: Option<usize>,
+    },
+}
+
+/// published to `REVOCATION_TOPIC`, the node applied it already
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeRevocation {
+    pub node: String,
+    pub revocation: Revocation,
+}
+
+fn now() -> usize {
+    chrono::Utc::now().timestamp() as usize
+}
+
+fn jti_key(jti: &str) -> String {
+    format!("revoked:jti:{}", jti)
+}
+
+fn user_key(user: &str) -> String {
+    format!("revoked:user:{}", user)
+}
+
+impl Revocation {
+    pub fn matches(&self, claims: &Claims) -> bool {
+        match self {
+            Revocation::Token { jti, .. } => claims.jti.as_ref() == Some(jti),
+            Revocation::User { user, at } => *user == claims.id && claims.iat.is_none_or(|iat| iat <= at.unwrap_or(usize::MAX)),
+        }
+    }
+
+    /// seconds until the tokens it revokes expire with the leeway, `None` keeps it forever without a `max_lifetime`
+    fn ttl(&self, max_lifetime: Option<u64>, leeway: u64) -> Option<u64> {
+        let now = now() as u64;
+        let expires = match self {
+            Revocation::Token { exp: Some(exp), .. } => *exp as u64 + leeway,
+            Revocation::Token { exp: None, .. } => now + max_lifetime? + leeway,
+            Revocation::User { at, .. } => at.map_or(now, |at| at as u64).max(now) + max_lifetime? + leeway,
+        };
+        Some(expires.saturating_sub(now).max(1))
+    }
+
+    /// store it in redis until the tokens it revokes expire; the `at` of a user only moves forward
+    async fn store(&self, redis_conn: &mut MultiplexedConnection, max_lifetime: Option<u64>, leeway: u64) -> RedisResult<()> {
+        let ttl = self.ttl(max_lifetime, leeway).unwrap_or(0);
+        match self {
+            Revocation::Token { jti, .. } if ttl == 0 => redis_conn.set(jti_key(jti), 1).await,
+            Revocation::Token { jti, .. } => redis_conn.set_ex(jti_key(jti), 1, ttl).await,
+            Revocation::User { user, at } => {
+                // a later `at` revokes more tokens and lives longer, an earlier one is kept out
+                let script = redis::Script::new(
+                    r"
+                    local at = tonumber(redis.call('GET', KEYS[1]) or '-1')
+                    if tonumber(ARGV[1]) <= at then return 0 end
+                    if ARGV[2] == '0' then redis.call('SET', KEYS[1], ARGV[1]) else redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2]) end
+                    return 1
+                    ",
+                );
+                let _: i32 = script
+                    .key(user_key(user))
+                    .arg(at.unwrap_or_else(now))
+                    .arg(ttl)
+                    .invoke_async(redis_conn)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// whether the token of the claims is revoked, by `jti` or by user
+pub async fn is_revoked(redis_conn: &mut MultiplexedConnection, claims: &Claims) -> RedisResult<bool> {
+    if let Some(jti) = &claims.jti {
+        if redis_conn.exists(jti_key(jti)).await? {
+            return Ok(true);
+        }
+    }
+    let revoked_at: Option<usize> = redis_conn.get(user_key(&claims.id)).await?;
+    Ok(revoked_at.is_some_and(|at| {
+        Revocation::User {
+            user: claims.id.clone(),
+            at: Some(at),
+        }
+        .matches(claims)
+    }))
+}
+
+/// store the revocation, disconnect the revoked agents of this node and tell the other nodes
+pub async fn revoke(state: Arc<State>, mut revocation: Revocation) -> RedisResult<usize> {
+    if let Revocation::User { at, .. } = &mut revocation {
+        at.get_or_insert_with(now);
+    }
+    let mut redis_conn = state.redis_client.get_multiplexed_async_connection().await?;
+    revocation.store(&mut redis_conn, state.jwt.max_lifetime(), state.jwt.leeway()).await?;
+
+    let disconnected = disconnect_revoked(state.clone(), &revocation).await;
+    let node_revocation = NodeRevocation {
+        node: state.node_id.clone(),
+        revocation,
+    };
+    let _: () = redis_conn
+        .publish(REVOCATION_TOPIC, serde_json::to_string(&node_revocation).unwrap())
+        .await?;
+    Ok(disconnected)
+}
+
+/// close the connections with a revoked agent, `1008` with `token revoked`; SSE streams end
+///
+/// the agents of a connection leave their channels with it, presence leaves are published; returns the closed connections
+pub async fn disconnect_revoked(state: Arc<State>, revocation: &Revocation) -> usize {
+    let ctl = state.ctl.lock().await;
+    let revoked = ctl
+        .agents
+        .lock()
+        .await
+        .values()
+        .filter(|agent| agent.claims.as_ref().is_some_and(|claims| revocation.matches(claims)))
+        .map(|agent| agent.id.clone())
+        .collect::<Vec<_>>();
+    let conn_ids = ctl.conn_ids().await;
+    drop(ctl);
+
+    let mut closed = HashSet::new();
+    for agent_id in revoked {
+        let conn_id = agent_id.split(':').next().unwrap_or_default().to_string();
+        if !conn_ids.contains(&conn_id) {
+            // an SSE subscriber, its stream ends and leaves the channel
+            terminate(&state, &agent_id, "revoked").await;
+            let close = ChannelMessage::Close {
+                code: CLOSE_POLICY_VIOLATION,
+                reason: "token revoked".into(),
+            };
+            state.ctl.lock().await.agent_send(&agent_id, close).await;
+            continue;
+        }
+        if !closed.insert(conn_id.clone()) {
+            continue;
+        }
+        terminate_conn(&state, &conn_id, "revoked").await;
+        let ctl = state.ctl.lock().await;
+        let close = ChannelMessage::Close {
+            code: CLOSE_POLICY_VIOLATION,
+            reason: "token revoked".into(),
+        };
+        let _ = ctl.conn_send(conn_id.clone(), close).await;
+        ctl.conn_cleanup(conn_id.clone()).await;
+        info!("REVOKE / connection {} closed, agent {} revoked", conn_id, agent_id);
+    }
+    closed.len()
+}
+
+/// apply the revocations of the other nodes
+pub async fn listen_to_revocations(state: Arc<State>) -> RedisResult<()> {
+    let mut redis_pubsub = state.redis_client.get_async_pubsub().await?;
+    redis_pubsub.subscribe(REVOCATION_TOPIC).await?;
+    let mut redis_pubsub_stream = redis_pubsub.on_message();
+    info!("REVOKE / subscribed to redis, node: {}", state.node_id);
+
+    while let Some(stream_message) = redis_pubsub_stream.next().await {
+        let payload: String = stream_message.get_payload()?;
+        let node_revocation = match serde_json::from_str::<NodeRevocation>(&payload) {
+            Ok(node_revocation) => node_revocation,
+            Err(e) => {
+                warn!("REVOKE / fail to deserialize, {}, payload: `{}`", e, payload);
+                continue;
+            }
+        };
+        if node_revocation.node == state.node_id {
+            continue; // applied by `revoke`
+        }
+        let closed = disconnect_revoked(state.clone(), &node_revocation.revocation).await;
+        info!("REVOKE / from {}, {:?}, closed: {}", node_revocation.node, node_revocation.revocation, closed);
+    }
+
+    error!("REVOKE / redis stream ended");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(id: &str, iat: Option<usize>, jti: Option<&str>) -> Claims {
+        Claims {
+            id: id.into(),
+            iat,
+            jti: jti.map(String::from),
+            ..Claims::default()
+        }
+    }
+
+    #[test]
+    fn test_revocation_matches() {
+        let token: Revocation = serde_json::from_str(r#"{"jti": "t1"}"#).unwrap();
+        assert_eq!(token, Revocation::Token { jti: "t1".into(), exp: None });
+        assert!(token.matches(&claims("alice", None, Some("t1"))));
+        assert!(!token.matches(&claims("alice", None, Some("t2"))));
+
+        let user: Revocation = serde_json::from_str(r#"{"user": "alice", "at": 100}"#).unwrap();
+        assert!(user.matches(&claims("alice", Some(100), None)));
+        assert!(user.matches(&claims("alice", None, None)), "no iat, issued any time");
+        assert!(!user.matches(&claims("alice", Some(101), None)), "issued after the revocation");
+        assert!(!user.matches(&claims("bob", Some(1), None)));
+    }
+
+    #[test]
+    fn test_revocation_ttl() {
+        let now = now();
+        let token = |exp| Revocation::Token { jti: "t1".into(), exp };
+        assert_eq!(token(Some(now + 600)).ttl(None, 60), Some(660), "until exp with the leeway");
+        assert_eq!(token(Some(now - 600)).ttl(None, 60), Some(1));
+        assert_eq!(token(None).ttl(Some(3600), 60), Some(3660));
+        assert_eq!(token(None).ttl(None, 60), None, "no max_lifetime, forever");
+
+        let user = |at| Revocation::User { user: "alice".into(), at };
+        assert_eq!(user(Some(now - 600)).ttl(Some(3600), 60), Some(3660), "tokens issued until now live as long");
+        assert_eq!(user(Some(now + 600)).ttl(Some(3600), 60), Some(4260));
+        assert_eq!(user(None).ttl(None, 60), None);
+    }
+
+    #[tokio::test]
+    async fn test_revocation_disconnect() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        let agents = [
+            ("c1", "c1:room:1", claims("alice", None, Some("t1"))),
+            ("c2", "c2:room:1", claims("bob", None, Some("t2"))),
+        ];
+        let ctl = state.ctl.lock().await;
+        ctl.channel_add("room".into(), None).await;
+        for (conn_id, agent_id, claims) in agents.iter() {
+            ctl.conn_add(conn_id.to_string(), "websocket", None).await;
+            ctl.agent_add(agent_id.to_string(), None).await;
+            ctl.channel_join("room", agent_id.to_string(), claims.id.clone()).await.unwrap();
+            ctl.agent_set_claims(agent_id, claims.clone()).await;
+        }
+        ctl.agent_add("s1:room:sse".into(), None).await;
+        ctl.channel_join("room", "s1:room:sse".into(), "bob".into()).await.unwrap();
+        ctl.agent_set_claims("s1:room:sse", claims("bob", Some(1), None)).await;
+        let mut c1_rx = ctl.conn_rx("c1".into()).await.unwrap();
+        let mut sse_rx = ctl.agent_rx("s1:room:sse".into()).await.unwrap();
+        drop(ctl);
+
+        let closed = disconnect_revoked(state.clone(), &Revocation::Token { jti: "t1".into(), exp: None }).await;
+        assert_eq!(closed, 1);
+        let Ok(ChannelMessage::Close { code, .. }) = c1_rx.try_recv() else {
+            panic!("closed")
+        };
+        assert_eq!(code, CLOSE_POLICY_VIOLATION);
+        assert!(!state.ctl.lock().await.conn_exists("c1").await);
+        assert!(state.ctl.lock().await.conn_exists("c2").await);
+
+        let closed = disconnect_revoked(
+            state.clone(),
+            &Revocation::User {
+                user: "bob".into(),
+                at: Some(1),
+            },
+        )
+        .await;
+        assert_eq!(closed, 1, "c2, the SSE stream isn't a connection");
+        assert!(matches!(sse_rx.try_recv(), Ok(ChannelMessage::Close { .. })));
+        assert!(!state.ctl.lock().await.conn_exists("c2").await);
+    }
+}
@@ -0,0 +1,254 @@
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+use crate::websocket::{RequestMessage, ServerMessage};
+
+/// wire format of a websocket connection, negotiated with `?serializer=` on `/websocket`
+///
+/// Redis producers and consumers always see JSON, only the websocket side is converted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Serializer {
+    /// JSON text frames, the Phoenix default
+    #[default]
+    Json,
+
+    /// MessagePack binary frames
+    #[serde(alias = "messagepack")]
+    MsgPack,
+}
+
+/// a websocket frame, independent of axum or warp
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    /// size in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug)]
+pub enum SerializerError {
+    Json(serde_json::Error),
+    MsgPackEncode(rmp_serde::encode::Error),
+    MsgPackDecode(rmpv::decode::Error),
+}
+
+impl Error for SerializerError {}
+
+impl Display for SerializerError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerializerError::Json(e) => write!(formatter, "<Json: {}>", e),
+            SerializerError::MsgPackEncode(e) => write!(formatter, "<MsgPackEncode: {}>", e),
+            SerializerError::MsgPackDecode(e) => write!(formatter, "<MsgPackDecode: {}>", e),
+        }
+    }
+}
+
+impl Display for Serializer {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Serializer::Json => write!(formatter, "json"),
+            Serializer::MsgPack => write!(formatter, "msgpack"),
+        }
+    }
+}
+
+impl Serializer {
+    /// encode a message for the websocket, text frame for JSON, binary frame for MessagePack
+    pub fn encode(&self, message: &ServerMessage) -> Result<Frame, SerializerError> {
+        match self {
+            Serializer::Json => serde_json::to_string(message).map(Frame::Text).map_err(SerializerError::Json),
+            // named, so that structs (e.g. `{"status": .., "response": ..}`) stay maps like in JSON
+            Serializer::MsgPack => rmp_serde::to_vec_named(message)
+                .map(Frame::Binary)
+                .map_err(SerializerError::MsgPackEncode),
+        }
+    }
+
+    /// decode a client message, the frame type decides the format
+    ///
+    /// MessagePack is converted to JSON first, so the payload published to Redis is the same as
+    /// if the client had sent JSON.
+    pub fn decode(&self, frame: &Frame) -> Result<RequestMessage, SerializerError> {
+        match frame {
+            Frame::Text(text) => serde_json::from_str(text).map_err(SerializerError::Json),
+            Frame::Binary(bytes) => serde_json::from_value(msgpack_to_json(bytes)?).map_err(SerializerError::Json),
+        }
+    }
+}
+
+/// convert MessagePack bytes into a JSON value
+///
+/// binary and extension data become arrays of bytes, non-string map keys are stringified
+pub fn msgpack_to_json(bytes: &[u8]) -> Result<serde_json::Value, SerializerError> {
+    let value = rmpv::decode::read_value(&mut &bytes[..]).map_err(SerializerError::MsgPackDecode)?;
+    Ok(rmpv_to_json(value))
+}
+
+fn rmpv_to_json(value: rmpv::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value {
+        rmpv::Value::Nil => Value::Null,
+        rmpv::Value::Boolean(b) => Value::Bool(b),
+        rmpv::Value::Integer(i) => match (i.as_i64(), i.as_u64()) {
+            (Some(n), _) => Value::from(n),
+            (_, Some(n)) => Value::from(n),
+            _ => Value::Null,
+        },
+        rmpv::Value::F32(f) => serde_json::Number::from_f64(f as f64).map_or(Value::Null, Value::Number),
+        rmpv::Value::F64(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+        rmpv::Value::String(s) => match s.into_str() {
+            Some(s) => Value::String(s),
+            None => Value::Null,
+        },
+        rmpv::Value::Binary(bytes) | rmpv::Value::Ext(_, bytes) => Value::Array(bytes.into_iter().map(Value::from).collect()),
+        rmpv::Value::Array(items) => Value::Array(items.into_iter().map(rmpv_to_json).collect()),
+        rmpv::Value::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        rmpv::Value::String(s) => s.into_str().unwrap_or_default(),
+                        other => other.to_string(),
+                    };
+                    (key, rmpv_to_json(value))
+                })
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::{RequestPayload, Response, ServerPayload, ServerResponse};
+    use serde_json::json;
+
+    fn server_message(join_ref: Option<&str>, event: &str, payload: ServerPayload) -> ServerMessage {
+        ServerMessage {
+            join_ref: join_ref.map(String::from),
+            event_ref: "7".to_string(),
+            topic: "telemetry".to_string(),
+            event: event.to_string(),
+            payload,
+        }
+    }
+
+    fn ok(response: Response) -> ServerPayload {
+        ServerPayload::ServerResponse(ServerResponse {
+            status: "ok".to_string(),
+            response,
+        })
+    }
+
+    fn round_trip_server(message: &ServerMessage) {
+        let expected = serde_json::to_value(message).unwrap();
+
+        let Frame::Text(text) = Serializer::Json.encode(message).unwrap() else {
+            panic!("json should be a text frame")
+        };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap(), expected);
+
+        let Frame::Binary(bytes) = Serializer::MsgPack.encode(message).unwrap() else {
+            panic!("msgpack should be a binary frame")
+        };
+        assert_eq!(msgpack_to_json(&bytes).unwrap(), expected);
+    }
+
+    fn round_trip_request(value: serde_json::Value) -> RequestMessage {
+        let from_json = Serializer::Json.decode(&Frame::Text(value.to_string())).unwrap();
+        let from_msgpack = Serializer::MsgPack.decode(&Frame::Binary(rmp_serde::to_vec(&value).unwrap())).unwrap();
+        assert_eq!(from_json, from_msgpack);
+        from_msgpack
+    }
+
+    #[test]
+    fn test_serializer_server_message_round_trip() {
+        round_trip_server(&server_message(Some("1"), "phx_reply", ok(Response::Join { id: "c:telemetry:1".into() })));
+        round_trip_server(&server_message(None, "phx_reply", ok(Response::Heartbeat {})));
+        round_trip_server(&server_message(None, "phx_reply", ok(Response::Empty {})));
+        round_trip_server(&server_message(
+            None,
+            "datetime",
+            ok(Response::Datetime {
+                datetime: "2024-01-01T00:00:00.000+00:00".into(),
+                counter: 42,
+            }),
+        ));
+        round_trip_server(&server_message(None, "message", ok(Response::Message { message: "hello".into() })));
+        round_trip_server(&server_message(
+            Some("1"),
+            "presence_state",
+            ServerPayload::ServerJsonValue(json!({"alice": {"metas": [{"phx_ref": "c:telemetry:1"}]}})),
+        ));
+        round_trip_server(&server_message(
+            None,
+            "sample",
+            ServerPayload::ServerJsonValue(json!({"cpu": 0.5, "mem": 1024, "tags": ["a", null, true], "nested": {"n": -3}})),
+        ));
+    }
+
+    #[test]
+    fn test_serializer_request_message_round_trip() {
+        let heartbeat = round_trip_request(json!([null, "1", "phoenix", "heartbeat", {}]));
+        assert_eq!(heartbeat.join_ref, None);
+        assert_eq!(heartbeat.payload, RequestPayload::JsonValue(json!({})));
+
+        let join = round_trip_request(json!(["1", "2", "telemetry", "phx_join", {"token": "jwt"}]));
+        assert_eq!(join.join_ref, Some("1".to_string()));
+        assert_eq!(
+            join.payload,
+            RequestPayload::Join {
+                token: "jwt".into(),
+                params: Default::default(),
+            }
+        );
+
+        let leave = round_trip_request(json!(["1", "3", "telemetry", "phx_leave", {}]));
+        assert_eq!(leave.event, "phx_leave");
+
+        let message = round_trip_request(json!(["1", "4", "telemetry", "message", {"message": "hi"}]));
+        assert_eq!(message.payload, RequestPayload::Message { message: "hi".into() });
+
+        let custom = round_trip_request(json!(["1", "5", "telemetry", "sample", {"cpu": 0.25, "cores": [1, 2]}]));
+        assert_eq!(custom.payload, RequestPayload::JsonValue(json!({"cpu": 0.25, "cores": [1, 2]})));
+    }
+
+    #[test]
+    fn test_serializer_msgpack_conversion() {
+        let value = rmpv::Value::Map(vec![
+            (rmpv::Value::from(1), rmpv::Value::from("one")),
+            (rmpv::Value::from("raw"), rmpv::Value::Binary(vec![1, 2])),
+        ]);
+        let mut bytes = vec![];
+        rmpv::encode::write_value(&mut bytes, &value).unwrap();
+        assert_eq!(msgpack_to_json(&bytes).unwrap(), json!({"1": "one", "raw": [1, 2]}));
+
+        assert!(Serializer::MsgPack.decode(&Frame::Binary(vec![0xc1])).is_err());
+    }
+
+    #[test]
+    fn test_serializer_query_value() {
+        assert_eq!(serde_json::from_value::<Serializer>(json!("json")).unwrap(), Serializer::Json);
+        assert_eq!(serde_json::from_value::<Serializer>(json!("msgpack")).unwrap(), Serializer::MsgPack);
+        assert!(serde_json::from_value::<Serializer>(json!("xml")).is_err());
+    }
+}
@@ -0,0 +1,647 @@
+use axum::{
+    extract::{ConnectInfo, Extension, Json, Query, State as AxumState, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use futures::StreamExt;
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::api::{self, has_api_key, listen_to_node_broadcast};
+use crate::config::Reloadable;
+use crate::expiry::expire_tokens;
+use crate::handler::{ChannelHandler, Handlers};
+use crate::health::{healthz, readyz};
+use crate::inbound::{InboundMiddleware, MessageLimits, Pipeline};
+use crate::jwt::{refresh_jwks, watch_keyring, Jwt, JwtConfig, JwtError};
+use crate::listen::RouteSet;
+use crate::longpoll::{longpoll_get, longpoll_post, LongPoll};
+use crate::metrics::{metrics_handler, METRICS};
+use crate::origin::Origins;
+use crate::ratelimit::{RateLimit, RateLimits};
+use crate::revocation::listen_to_revocations;
+use crate::serializer::Serializer;
+use crate::shutdown;
+use crate::sse::sse_handler;
+use crate::tls::ClientIdentity;
+use crate::utils::{random_string, Claims, TopicGrant};
+use crate::websocket::{add_channel, axum_on_connected, datetime_handler, launch_channel_redis_listen_task, State};
+
+/// channels every node has, with their redis listeners
+pub const SPECIAL_CHANNELS: [&str; 3] = ["phoenix", "admin", "system"];
+
+/// a channel server to mount in an axum app
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let server = channel::server::ChannelServer::builder()
+///     .redis_url("redis://127.0.0.1:6379")
+///     .jwt_secret("secret")
+///     .build()
+///     .await?;
+/// let app = axum::Router::new().nest("/socket", server.router());
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChannelServer {
+    state: Arc<State>,
+    tasks: Vec<JoinHandle<()>>, // keepalive, node broadcast, revocations, datetime, token expiry, jwks, key ring
+}
+
+pub struct ChannelServerBuilder {
+    redis_url: Option<String>,
+    redis_client: Option<redis::Client>,
+    jwt_secret: Option<String>,
+    jwt_expiration_secs: i64,
+    jwt: JwtConfig,
+    id_length: u8,
+    api_key: Option<String>,
+    longpoll_window: Duration,
+    longpoll_idle_timeout: Duration,
+    datetime: bool,
+    handlers: Handlers,
+    pipeline: Pipeline,
+    message_limits: MessageLimits,
+    token_refresh_window: Duration,
+    topic_policies: Vec<TopicGrant>,
+    origins: Origins,
+    rate_limit: Option<Arc<RateLimit>>,
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    MissingRedis,
+    Redis(redis::RedisError),
+    Jwt(JwtError),
+}
+
+impl Error for ServerError {}
+
+impl Display for ServerError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::MissingRedis => write!(formatter, "<MissingRedis: a redis url or client is required>"),
+            ServerError::Redis(e) => write!(formatter, "<Redis: {}>", e),
+            ServerError::Jwt(e) => write!(formatter, "<Jwt: {}>", e),
+        }
+    }
+}
+
+impl Default for ChannelServerBuilder {
+    fn default() -> Self {
+        ChannelServerBuilder {
+            redis_url: None,
+            redis_client: None,
+            jwt_secret: None,
+            jwt_expiration_secs: 259200, // 3 days
+            jwt: JwtConfig::default(),
+            id_length: 8,
+            api_key: None,
+            longpoll_window: Duration::from_secs(10),
+            longpoll_idle_timeout: Duration::from_secs(20),
+            datetime: true,
+            handlers: Handlers::default(),
+            pipeline: Pipeline::default(),
+            message_limits: MessageLimits::default(),
+            token_refresh_window: Duration::from_secs(60),
+            topic_policies: vec![],
+            origins: Origins::default(),
+            rate_limit: None,
+        }
+    }
+}
+
+impl ChannelServerBuilder {
+    pub fn redis_url(mut self, redis_url: impl Into<String>) -> Self {
+        self.redis_url = Some(redis_url.into());
+        self
+    }
+
+    /// use an existing redis client, instead of `redis_url`
+    pub fn redis_client(mut self, redis_client: redis::Client) -> Self {
+        self.redis_client = Some(redis_client);
+        self
+    }
+
+    /// HS256 secret of the tokens, a random one is generated if missing
+    pub fn jwt_secret(mut self, jwt_secret: impl Into<String>) -> Self {
+        self.jwt_secret = Some(jwt_secret.into());
+        self
+    }
+
+    /// expiration of the tokens from `POST /token`
+    pub fn jwt_expiration_secs(mut self, jwt_expiration_secs: i64) -> Self {
+        self.jwt_expiration_secs = jwt_expiration_secs;
+        self
+    }
+
+    /// algorithms, public keys and JWKS the tokens are verified with, the signing key of `POST /token`
+    pub fn jwt(mut self, jwt: JwtConfig) -> Self {
+        self.jwt = jwt;
+        self
+    }
+
+    /// length of the generated ids
+    pub fn id_length(mut self, id_length: u8) -> Self {
+        self.id_length = id_length;
+        self
+    }
+
+    /// bearer token of the HTTP API, the API is disabled without it
+    pub fn api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    pub fn longpoll(mut self, window: Duration, idle_timeout: Duration) -> Self {
+        self.longpoll_window = window;
+        self.longpoll_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// broadcast the datetime to the `system` channel every second, enabled by default
+    pub fn datetime(mut self, datetime: bool) -> Self {
+        self.datetime = datetime;
+        self
+    }
+
+    /// in-process logic of the topics matching `pattern`, e.g. `room:*`, instead of forwarding pushes to redis
+    pub fn handler(mut self, pattern: impl Into<String>, handler: impl ChannelHandler + 'static) -> Self {
+        self.handlers.register(pattern, Arc::new(handler));
+        self
+    }
+
+    /// inbound middleware run on every client message before the built-in steps, in the order they're added
+    pub fn inbound(mut self, middleware: impl InboundMiddleware + 'static) -> Self {
+        self.pipeline.layer(Arc::new(middleware));
+        self
+    }
+
+    /// frame and payload sizes, joins per connection
+    pub fn message_limits(mut self, message_limits: MessageLimits) -> Self {
+        self.message_limits = message_limits;
+        self
+    }
+
+    /// agents get `token_expiring` this long before their token expires, to send a new one with `phx_refresh`
+    pub fn token_refresh_window(mut self, token_refresh_window: Duration) -> Self {
+        self.token_refresh_window = token_refresh_window;
+        self
+    }
+
+    /// server-wide grants by topic pattern, e.g. read-only topics or allowed events; the first match applies, tokens can only narrow them
+    pub fn topic_policies(mut self, topic_policies: Vec<TopicGrant>) -> Self {
+        self.topic_policies = topic_policies;
+        self
+    }
+
+    /// origins allowed to open websockets and long-poll sessions, and to call the HTTP routes cross-origin; any if empty
+    pub fn origins(mut self, origins: Origins) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    /// token-bucket limits on joins, pushes and users, an inbound middleware like the ones added with `inbound`
+    pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        let rate_limit = Arc::new(RateLimit::new(rate_limits));
+        self.pipeline.layer(rate_limit.clone());
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// create the state, add the special channels and spawn the background tasks
+    pub async fn build(self) -> Result<ChannelServer, ServerError> {
+        let redis_client = match (self.redis_client, self.redis_url) {
+            (Some(redis_client), _) => redis_client,
+            (None, Some(redis_url)) => redis::Client::open(redis_url).map_err(ServerError::Redis)?,
+            (None, None) => return Err(ServerError::MissingRedis),
+        };
+        let jwt_secret = self.jwt_secret.unwrap_or_else(|| {
+            let random_secret = random_string(8);
+            warn!("no secret proviced, generated: {}", random_secret);
+            random_secret
+        });
+        let jwt = Jwt::load(&jwt_secret, self.jwt).await.map_err(ServerError::Jwt)?;
+        if self.api_key.is_none() {
+            warn!("no api key provided, the HTTP API is disabled");
+        }
+
+        let state = Arc::new(State {
+            id_length: self.id_length,
+            jwt_expiration_secs: self.jwt_expiration_secs,
+            longpoll: LongPoll::new(self.longpoll_window, self.longpoll_idle_timeout),
+            api_key: self.api_key,
+            handlers: self.handlers,
+            pipeline: self.pipeline,
+            message_limits: RwLock::new(self.message_limits),
+            token_refresh_window: self.token_refresh_window,
+            topic_policies: RwLock::new(self.topic_policies),
+            origins: self.origins,
+            rate_limit: self.rate_limit,
+            jwt,
+            ..State::new(redis_client, jwt_secret)
+        });
+        info!("node id: {}", state.node_id);
+        label_metrics(&state);
+
+        let mut tasks = vec![
+            spawn_logged("KEEPALIVE", keepalive(state.clone())),
+            spawn_logged("NODE_BROADCAST", listen_to_node_broadcast(state.clone())),
+            spawn_logged("REVOKE", listen_to_revocations(state.clone())),
+        ];
+        for channel_name in SPECIAL_CHANNELS {
+            add_channel(&state.ctl, channel_name.into()).await;
+            launch_channel_redis_listen_task(state.clone(), &state.ctl, channel_name.into(), state.redis_client.clone()).await;
+        }
+        if self.datetime {
+            tasks.push(tokio::spawn(datetime_handler(state.clone(), "system".into())));
+        }
+        tasks.push(tokio::spawn(expire_tokens(state.clone())));
+        tasks.push(tokio::spawn(refresh_jwks(state.clone())));
+        tasks.push(tokio::spawn(watch_keyring(state.clone())));
+
+        Ok(ChannelServer { state, tasks })
+    }
+}
+
+fn spawn_logged(name: &'static str, task: impl std::future::Future<Output = RedisResult<()>> + Send + 'static) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = task.await {
+            error!("{} / exited: {}", name, e);
+        }
+    })
+}
+
+impl ChannelServer {
+    pub fn builder() -> ChannelServerBuilder {
+        ChannelServerBuilder::default()
+    }
+
+    pub fn state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    /// every endpoint of the server, the state is applied, so it can be merged or nested in any app
+    ///
+    /// serve it with `into_make_service_with_connect_info::<SocketAddr>()` to record the remote addresses
+    pub fn router(&self) -> Router {
+        self.routes(&RouteSet::ALL)
+    }
+
+    /// the endpoints of some route sets, e.g. `Api` and `Metrics` for a listener of the private network
+    pub fn routes(&self, route_sets: &[RouteSet]) -> Router {
+        let mut router = Router::new();
+        for route_set in RouteSet::ALL.into_iter().filter(|route_set| route_sets.contains(route_set)) {
+            router = match route_set {
+                RouteSet::Socket => router
+                    .route("/websocket", get(websocket_handler))
+                    // phoenix.js replaces the trailing `/websocket` of the endpoint with `/longpoll`
+                    .route("/longpoll", get(longpoll_get).post(longpoll_post))
+                    .route("/socket/longpoll", get(longpoll_get).post(longpoll_post))
+                    .route("/sse/{topic}", get(sse_handler)),
+                RouteSet::Token => router.route("/token", post(generate_token)),
+                RouteSet::Api => router.merge(api::router(self.state.clone())),
+                RouteSet::Metrics => router.route("/metrics", get(metrics_handler)),
+                RouteSet::Health => router.route("/healthz", get(healthz)).route("/readyz", get(readyz)),
+            };
+        }
+        let router = router.with_state(self.state.clone());
+        match self.state.origins.cors_layer() {
+            Some(cors) => router.layer(cors),
+            None => router,
+        }
+    }
+
+    /// the limits, policies and rate limits in use
+    pub fn reloadable(&self) -> Reloadable {
+        Reloadable {
+            message_limits: self.state.message_limits.read().unwrap().clone(),
+            topic_policies: self.state.topic_policies.read().unwrap().clone(),
+            rate_limits: self.state.rate_limit.as_ref().map(|rate_limit| rate_limit.limits()).unwrap_or_default(),
+        }
+    }
+
+    /// swap the limits, policies and rate limits, they apply to the next messages and joins, no connection is dropped
+    ///
+    /// rate limits need `ChannelServerBuilder::rate_limits` at startup, even without any limit, to be reloaded
+    pub fn reload(&self, reloadable: Reloadable) {
+        *self.state.message_limits.write().unwrap() = reloadable.message_limits;
+        *self.state.topic_policies.write().unwrap() = reloadable.topic_policies;
+        match &self.state.rate_limit {
+            Some(rate_limit) => rate_limit.set_limits(reloadable.rate_limits),
+            None if reloadable.rate_limits != RateLimits::default() => warn!("RELOAD / no rate limits at startup, restart to apply them"),
+            None => {}
+        }
+        label_metrics(&self.state);
+        info!("RELOAD / limits, policies and rate limits reloaded");
+    }
+
+    /// drain the connections, see `shutdown::drain`, then stop the background tasks
+    pub async fn shutdown(&self) {
+        shutdown::drain(self.state.clone()).await;
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenRequest {
+    channel: String,
+    id: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    topics: Vec<TopicGrant>,
+    #[serde(default)]
+    assigns: serde_json::Map<String, serde_json::Value>,
+}
+
+impl TokenRequest {
+    /// roles, grants and assigns are trusted by the server, only the backend can ask for them
+    fn is_privileged(&self) -> bool {
+        !self.roles.is_empty() || !self.topics.is_empty() || !self.assigns.is_empty()
+    }
+}
+
+#[derive(Debug)]
+enum TokenError {
+    // ChannelNotFound,
+    Unauthorized,
+    GenerationFailed,
+}
+
+impl IntoResponse for TokenError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            // TokenError::ChannelNotFound => (StatusCode::NOT_FOUND, "Channel not found"),
+            TokenError::Unauthorized => (StatusCode::UNAUTHORIZED, "Roles, topics and assigns need the api key"),
+            TokenError::GenerationFailed => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to generate token"),
+        };
+
+        (status, message).into_response()
+    }
+}
+
+async fn generate_token(
+    AxumState(state): AxumState<Arc<State>>, headers: HeaderMap, Json(req): Json<TokenRequest>,
+) -> Result<impl IntoResponse, TokenError> {
+    // Check if channel exists
+    // let ctl = state.ctl.lock().await;
+    // let channels = ctl.channels.lock().await;
+    // if !channels.contains_key(&req.channel) {
+    //     error!("channel {} not found", req.channel);
+    //     return Err(TokenError::ChannelNotFound);
+    // }
+    if req.is_privileged() && !has_api_key(&state, &headers) {
+        warn!("TOKEN / roles, topics or assigns without the api key, channel: {}", req.channel);
+        return Err(TokenError::Unauthorized);
+    }
+    let id_length = state.id_length as usize;
+    let id = req
+        .id
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(|| nanoid::nanoid!(id_length).to_string());
+
+    let claims = Claims {
+        id: id.clone(),
+        channel: req.channel.clone(),
+        roles: req.roles,
+        topics: req.topics,
+        assigns: req.assigns,
+        ..Claims::default()
+    };
+    match state.jwt.sign(claims, state.jwt_expiration_secs).await {
+        Ok(token) => Ok(Json(serde_json::json!({
+            "id": id.clone(),
+            "channel": req.channel.clone(),
+            "token": token
+        }))),
+        Err(e) => {
+            error!("TOKEN / fail to sign: {}", e);
+            Err(TokenError::GenerationFailed)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WebSocketParams {
+    #[serde(rename = "userToken")]
+    user_token: Option<String>,
+
+    #[serde(rename = "vsn")]
+    version: String,
+
+    #[serde(default)]
+    serializer: Serializer,
+}
+
+async fn websocket_handler(
+    ws: WebSocketUpgrade, remote: Option<Extension<ConnectInfo<SocketAddr>>>, client_identity: Option<Extension<ClientIdentity>>,
+    Query(params): Query<WebSocketParams>, headers: HeaderMap, AxumState(state): AxumState<Arc<State>>,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response();
+    }
+    if state.origins.rejected(&headers, "websocket").is_some() {
+        return (StatusCode::FORBIDDEN, "origin not allowed").into_response();
+    }
+    info!("version: {}, serializer: {}", params.version, params.serializer);
+    let remote_addr = remote.map(|Extension(ConnectInfo(remote_addr))| remote_addr);
+    let client_identity = client_identity.map(|Extension(client_identity)| client_identity);
+    // bigger frames fail the read before they're buffered, a reload applies to the next connections
+    let max_frame_size = state.message_limits.read().unwrap().max_frame_size;
+    ws.max_message_size(max_frame_size)
+        .max_frame_size(max_frame_size)
+        .on_upgrade(move |socket| axum_on_connected(socket, state, params.user_token.clone(), params.serializer, remote_addr, client_identity))
+        .into_response()
+}
+
+/// the metrics are labeled by the topic patterns of the policies, rate limits and handlers, and the events of the policies
+fn label_metrics(state: &State) {
+    let (mut patterns, events): (Vec<_>, Vec<_>) = state
+        .topic_policies
+        .read()
+        .unwrap()
+        .iter()
+        .map(|policy| (policy.topic.clone(), policy.events.clone()))
+        .unzip();
+    if let Some(rate_limit) = &state.rate_limit {
+        patterns.extend(rate_limit.limits().topics.into_iter().map(|(pattern, _)| pattern));
+    }
+    patterns.extend(state.handlers.patterns().map(String::from));
+    METRICS.set_topic_patterns(patterns);
+    METRICS.set_events(events.into_iter().flatten().collect());
+}
+
+async fn keepalive(state: Arc<State>) -> RedisResult<()> {
+    let redis_client = state.redis_client.clone();
+
+    let redis_topic = "from:*:heartbeat".to_string();
+    let mut redis_pubsub = redis_client.get_async_pubsub().await?;
+    redis_pubsub.psubscribe(redis_topic.clone()).await?;
+    let mut redis_pubsub_stream = redis_pubsub.on_message();
+
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            optional_message = redis_pubsub_stream.next() => {
+                if optional_message.is_none() {
+                    error!("KEEPALIVE / from redis: none");
+                    continue;
+                }
+                // payload JSON: {"conn_id": conn_id}
+                let payload = optional_message.unwrap().get_payload::<String>().unwrap();
+                let value_result: serde_json::Result<serde_json::Value> = serde_json::from_str(&payload);
+                if value_result.is_err() {
+                    error!("KEEPALIVE / from redis: parse error: {}", payload);
+                    continue;
+                }
+                if let Some(conn_id) = value_result.unwrap()["conn_id"].as_str() {
+                    info!("KEEPALIVE / heartbeat {:?}", conn_id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_server_builder() {
+        assert!(matches!(ChannelServer::builder().build().await, Err(ServerError::MissingRedis)));
+
+        let server = ChannelServer::builder()
+            .redis_url("redis://127.0.0.1:1")
+            .jwt_secret("secret")
+            .datetime(false)
+            .build()
+            .await
+            .unwrap();
+        for channel_name in SPECIAL_CHANNELS {
+            assert!(server.state().ctl.lock().await.channel_exists(channel_name).await);
+        }
+
+        let app = Router::new().nest("/socket", server.router());
+        let response = app
+            .clone()
+            .oneshot(axum::http::Request::get("/socket/healthz").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = axum::http::Request::post("/socket/token")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"channel": "room", "id": "alice"}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["id"], "alice");
+        let claims = Jwt::from_secret("secret").decode(body["token"].as_str().unwrap()).await.unwrap();
+        assert_eq!(claims.id, "alice");
+
+        let request = axum::http::Request::post("/socket/token")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"channel": "room", "id": "alice", "roles": ["admin"]}"#))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "roles need the api key");
+
+        server.shutdown().await;
+        assert!(server.state().draining.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_server_origins() {
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Error as WsError};
+
+        let server = ChannelServer::builder()
+            .redis_url("redis://127.0.0.1:1")
+            .datetime(false)
+            .origins(Origins::new(["https://*.example.com"]))
+            .build()
+            .await
+            .unwrap();
+        let app = server.router();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serve = axum::serve(listener, app.clone());
+        tokio::spawn(async move { serve.await.unwrap() });
+
+        let connect = async |origin: &'static str| {
+            let mut request = format!("ws://{}/websocket?vsn=2.0.0", addr).into_client_request().unwrap();
+            request.headers_mut().insert("origin", origin.parse().unwrap());
+            tokio_tungstenite::connect_async(request).await
+        };
+        let Err(WsError::Http(response)) = connect("https://evil.test").await else {
+            panic!("rejected")
+        };
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(connect("https://app.example.com").await.is_ok());
+
+        let preflight = |origin: &'static str| {
+            axum::http::Request::builder()
+                .method("OPTIONS")
+                .uri("/token")
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(preflight("https://app.example.com")).await.unwrap();
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+        let response = app.oneshot(preflight("https://evil.test")).await.unwrap();
+        assert!(!response.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn test_server_reload() {
+        let server = ChannelServer::builder()
+            .redis_url("redis://127.0.0.1:1")
+            .datetime(false)
+            .rate_limits(RateLimits::default())
+            .build()
+            .await
+            .unwrap();
+        let claims = Claims::default();
+        assert_eq!(server.reloadable(), Reloadable::default());
+        assert!(server.state.can_push(&claims, "news:1", "shout"));
+
+        let reloadable = Reloadable {
+            message_limits: MessageLimits {
+                max_joins: 2,
+                ..MessageLimits::default()
+            },
+            topic_policies: serde_json::from_value(serde_json::json!([{"topic": "news:*", "write": false}])).unwrap(),
+            rate_limits: RateLimits {
+                default: crate::ratelimit::Limits {
+                    push: Some("1/1".parse().unwrap()),
+                    ..Default::default()
+                },
+                ..RateLimits::default()
+            },
+        };
+        server.reload(reloadable.clone());
+        assert_eq!(server.reloadable(), reloadable);
+        assert!(!server.state.can_push(&claims, "news:1", "shout") && server.state.can_read(&claims, "news:1"));
+        assert_eq!(server.state.message_limits.read().unwrap().max_joins, 2);
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::{atomic::Ordering, Arc};
+use tracing::{error, info};
+
+use crate::channel::{ChannelMessage, CLOSE_GOING_AWAY};
+use crate::handler::terminate;
+use crate::websocket::{presence_diff, PresenceAction, State};
+
+/// resolves on SIGINT (ctrl-c) or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("fail to listen to ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("fail to listen to SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("SHUTDOWN / ctrl-c received"),
+        _ = terminate => info!("SHUTDOWN / SIGTERM received"),
+    }
+}
+
+/// drain the node before exiting
+///
+/// new connections are refused, channel handlers are terminated, every agent gets `phx_close`, every connection a close frame with "going away",
+/// presence leaves are published for every agent, then the redis listeners stop
+pub async fn drain(state: Arc<State>) {
+    state.draining.store(true, Ordering::SeqCst);
+
+    // handlers run first, they may still push or broadcast
+    let agent_ids = state.sockets.lock().await.keys().cloned().collect::<Vec<_>>();
+    for agent_id in agent_ids {
+        terminate(&state, &agent_id, "shutdown").await;
+    }
+
+    let ctl = state.ctl.lock().await;
+
+    let agents = ctl
+        .agents
+        .lock()
+        .await
+        .values()
+        .map(|agent| (agent.id.clone(), agent.channel.clone(), agent.external_id.clone(), agent.meta.clone()))
+        .collect::<Vec<_>>();
+    for (agent_id, channel_name, _, _) in agents.iter() {
+        ctl.agent_send_close(agent_id, channel_name).await;
+    }
+
+    // the close is delivered before the connection's sender is dropped, `conn_cleanup` publishes the presence leaves
+    let conn_ids = ctl.conn_ids().await;
+    for conn_id in conn_ids.iter() {
+        let close = ChannelMessage::Close {
+            code: CLOSE_GOING_AWAY,
+            reason: "server shutting down".into(),
+        };
+        let _ = ctl.conn_send(conn_id.clone(), close).await;
+        ctl.conn_cleanup(conn_id.clone()).await;
+    }
+    info!("SHUTDOWN / {} connections closed", conn_ids.len());
+
+    // agents without a connection, e.g. SSE subscribers
+    let orphans = agents
+        .into_iter()
+        .filter(|(agent_id, ..)| !conn_ids.iter().any(|conn_id| agent_id.starts_with(&format!("{}:", conn_id))))
+        .collect::<Vec<_>>();
+    if !orphans.is_empty() {
+        let mut redis_conn = state.redis_client.get_multiplexed_async_connection().await;
+        for (agent_id, channel_name, external_id, meta) in orphans {
+            ctl.agent_rm(agent_id.clone()).await;
+            match redis_conn.as_mut() {
+                Ok(redis_conn) => presence_diff(redis_conn, channel_name, agent_id, external_id, meta, PresenceAction::Leave).await,
+                Err(e) => error!("SHUTDOWN / fail to get redis connection: {}", e),
+            }
+        }
+    }
+
+    for channel in ctl.channels.lock().await.values() {
+        if let Some(task) = &channel.redis_listen_task {
+            task.abort();
+        }
+    }
+    info!("SHUTDOWN / drained");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_shutdown_drain() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        {
+            let ctl = state.ctl.lock().await;
+            ctl.channel_add("room".into(), None).await;
+            ctl.conn_add("c1".into(), "websocket", None).await;
+            for agent_id in ["c1:room:1", "s1:room:sse"] {
+                ctl.agent_add(agent_id.into(), None).await;
+                ctl.channel_join_with_meta("room", agent_id.into(), "alice".into(), json!({}))
+                    .await
+                    .unwrap();
+            }
+        }
+        let mut conn_rx = state.ctl.lock().await.conn_rx("c1".into()).await.unwrap();
+
+        drain(state.clone()).await;
+
+        assert!(state.draining.load(Ordering::SeqCst));
+        let ChannelMessage::Reply(phx_close) = conn_rx.recv().await.unwrap() else {
+            panic!("phx_close first")
+        };
+        assert_eq!((phx_close.topic.as_str(), phx_close.event.as_str()), ("room", "phx_close"));
+        let ChannelMessage::Close { code, .. } = conn_rx.recv().await.unwrap() else {
+            panic!("then the close")
+        };
+        assert_eq!(code, CLOSE_GOING_AWAY);
+        assert!(conn_rx.recv().await.is_err(), "connection is cleaned up");
+
+        let ctl = state.ctl.lock().await;
+        assert!(ctl.agents.lock().await.is_empty());
+        assert!(ctl.conn_list().await.is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+use axum::{
+    extract::{Path, Query, State as AxumState},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    sync::{atomic::Ordering, Arc},
+};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{error, info, warn};
+
+use crate::channel::ChannelMessage;
+use crate::metrics::METRICS;
+use crate::revocation::is_revoked;
+use crate::utils::Claims;
+use crate::websocket::{
+    add_channel, is_special_channel, launch_channel_redis_listen_task, presence_diff, PresenceAction, ServerMessage, ServerPayload, State,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SseParams {
+    token: String,
+}
+
+/// read-only member of a channel, leaves the channel when the SSE stream is dropped
+struct SseSubscription {
+    state: Arc<State>,
+    agent_id: String,
+    channel_name: String,
+    external_id: String,
+    meta: serde_json::Value,
+}
+
+impl Drop for SseSubscription {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let agent_id = self.agent_id.clone();
+        let channel_name = self.channel_name.clone();
+        let external_id = self.external_id.clone();
+        let meta = self.meta.clone();
+        tokio::spawn(async move {
+            info!("SSE / {} disconnected", agent_id);
+            state.ctl.lock().await.agent_rm(agent_id.clone()).await;
+            let agent_count = state.ctl.lock().await.channel_leave(channel_name.clone(), agent_id.clone()).await;
+            if agent_count == Ok(0) && !is_special_channel(&channel_name) {
+                state.ctl.lock().await.channel_rm(channel_name.clone()).await;
+            }
+            match state.redis_client.get_multiplexed_async_connection().await {
+                Ok(mut redis_conn) => presence_diff(&mut redis_conn, channel_name, agent_id, external_id, meta, PresenceAction::Leave).await,
+                Err(e) => error!("SSE / fail to get redis connection: {}", e),
+            }
+        });
+    }
+}
+
+/// `{"read_only": true}` and the assigns of the token
+fn read_only_meta(claims: &Claims) -> serde_json::Value {
+    let mut meta = claims.presence_meta();
+    meta["read_only"] = json!(true);
+    meta
+}
+
+/// SSE event of a channel message, `event` is the event name and `data` the payload
+///
+/// the id is the sequence number of messages from redis in the channel's history, so that clients can resume with `Last-Event-ID`
+fn to_event(message: &ServerMessage, seq: Option<u64>) -> Event {
+    let data = match &message.payload {
+        ServerPayload::ServerJsonValue(value) => value.to_string(),
+        payload @ ServerPayload::ServerResponse(_) => serde_json::to_string(payload).unwrap_or_default(),
+    };
+    let event = Event::default().event(&message.event).data(data);
+    match seq {
+        Some(seq) => event.id(seq.to_string()),
+        None => event,
+    }
+}
+
+/// `GET /sse/{topic}?token=...`, stream the broadcasts of a channel as server-sent events
+pub async fn sse_handler(
+    Path(channel_name): Path<String>, Query(params): Query<SseParams>, headers: HeaderMap, AxumState(state): AxumState<Arc<State>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, &'static str)> {
+    if state.draining.load(Ordering::SeqCst) {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "shutting down"));
+    }
+    if state.origins.rejected(&headers, "sse").is_some() {
+        return Err((StatusCode::FORBIDDEN, "origin not allowed"));
+    }
+    let claims = state.jwt.decode(&params.token).await.map_err(|e| {
+        error!("SSE / fail to decode JWT, {}", e);
+        (StatusCode::UNAUTHORIZED, "invalid token")
+    })?;
+    let revoked = match state.redis_client.get_multiplexed_async_connection().await {
+        Ok(mut redis_conn) => is_revoked(&mut redis_conn, &claims).await,
+        Err(e) => Err(e),
+    };
+    match revoked {
+        Ok(false) => {}
+        Ok(true) => {
+            warn!("SSE / token of {} revoked, jti: {:?}", claims.id, claims.jti);
+            return Err((StatusCode::UNAUTHORIZED, "token revoked"));
+        }
+        Err(e) => error!("SSE / fail to check the revocations: {}", e),
+    }
+    if !state.can_read(&claims, &channel_name) {
+        warn!("SSE / token of {} doesn't grant {}", claims.id, channel_name);
+        return Err((StatusCode::FORBIDDEN, "topic not granted"));
+    }
+
+    if !is_special_channel(&channel_name) {
+        add_channel(&state.ctl, channel_name.clone()).await;
+        launch_channel_redis_listen_task(state.clone(), &state.ctl, channel_name.clone(), state.redis_client.clone()).await;
+    }
+
+    let meta = read_only_meta(&claims);
+    let conn_id = nanoid::nanoid!(8).to_string();
+    let agent_id = format!("{}:{}:sse", conn_id, channel_name);
+    let ctl = state.ctl.lock().await;
+    ctl.agent_add(agent_id.clone(), None).await;
+    let joined = ctl
+        .channel_join_with_meta(&channel_name, agent_id.clone(), claims.id.clone(), meta.clone())
+        .await;
+    if let Err(e) = joined {
+        error!("SSE / fail to join: {}", e);
+        ctl.agent_rm(agent_id.clone()).await;
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "fail to join"));
+    }
+    ctl.agent_set_claims(&agent_id, claims.clone()).await;
+    let agent_rx = ctl
+        .agent_rx(agent_id.clone())
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "fail to join"))?;
+
+    // subscribed before reading the history, so nothing is lost in between; duplicates are skipped below
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let replay = match last_event_id {
+        Some(last_seq) => ctl
+            .channels
+            .lock()
+            .await
+            .get(&channel_name)
+            .map(|channel| channel.history.since(last_seq))
+            .unwrap_or_default(),
+        None => vec![],
+    };
+    drop(ctl);
+    info!("SSE / {} subscribed, claims: {:?}, replay: {}", agent_id, claims, replay.len());
+
+    match state.redis_client.get_multiplexed_async_connection().await {
+        Ok(mut redis_conn) => {
+            presence_diff(&mut redis_conn, channel_name.clone(), agent_id.clone(), claims.id.clone(), meta.clone(), PresenceAction::Join).await
+        }
+        Err(e) => error!("SSE / fail to get redis connection: {}", e),
+    }
+
+    let replayed: HashSet<u64> = replay.iter().map(|(seq, _)| *seq).collect();
+    let subscription = SseSubscription {
+        state: state.clone(),
+        agent_id,
+        channel_name,
+        external_id: claims.id,
+        meta,
+    };
+    // a close, e.g. of a revoked token, ends the stream
+    let live = BroadcastStream::new(agent_rx)
+        .take_while(|result| {
+            let open = !matches!(result, Ok(ChannelMessage::Close { .. }));
+            async move { open }
+        })
+        .filter_map(move |result| {
+            let _subscription = &subscription; // dropped with the stream
+            let event = match result {
+                Ok(channel_message) => {
+                    let received_at = channel_message.received_at();
+                    let seq = channel_message.seq();
+                    match channel_message.into_reply() {
+                        Some(message) if !seq.is_some_and(|seq| replayed.contains(&seq)) => {
+                            METRICS.message_out(&message.topic, &message.event);
+                            METRICS.delivered("sse", received_at);
+                            Some(Ok(to_event(&message, seq)))
+                        }
+                        _ => None,
+                    }
+                }
+                Err(BroadcastStreamRecvError::Lagged(n)) => {
+                    warn!("SSE / lagged, {} messages dropped", n);
+                    METRICS.lagged("sse", n);
+                    None
+                }
+            };
+            async move { event }
+        });
+    let replay = stream::iter(replay.into_iter().map(|(seq, message)| Ok(to_event(&message, Some(seq)))));
+
+    Ok(Sse::new(replay.chain(live)).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{Channel, HISTORY_CAPACITY};
+    use crate::origin::Origins;
+    use crate::utils::Claims;
+    use axum::{response::IntoResponse, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn message(seq: u64, event: &str) -> ServerMessage {
+        ServerMessage {
+            join_ref: None,
+            event_ref: "0".into(),
+            topic: "metrics".into(),
+            event: event.into(),
+            payload: ServerPayload::ServerJsonValue(json!({"seq": seq})),
+        }
+    }
+
+    #[test]
+    fn test_sse_history_since() {
+        let channel = Channel::new("metrics".into(), None);
+        for n in 1..=HISTORY_CAPACITY as u64 + 5 {
+            let (seq, message) = channel.history.push(message(n, "tick"));
+            assert_eq!((seq, message.event_ref), (n, n.to_string()));
+        }
+        let events = channel.history.since(HISTORY_CAPACITY as u64 + 3);
+        assert_eq!(events.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![104, 105]);
+        assert_eq!(channel.history.since(0).len(), HISTORY_CAPACITY, "the oldest are dropped");
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+        let state = Arc::new(State::new(redis_client, "secret".into()));
+        state.ctl.lock().await.channel_add("system".into(), None).await;
+        for seq in 1..=3 {
+            let ctl = state.ctl.lock().await;
+            let channels = ctl.channels.lock().await;
+            channels.get("system").unwrap().history.push(message(seq, "tick"));
+        }
+
+        let app = Router::new().route("/sse/{topic}", get(sse_handler)).with_state(state.clone());
+        let unauthorized = app
+            .clone()
+            .oneshot(axum::http::Request::get("/sse/system?token=bad").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+        state.draining.store(true, Ordering::SeqCst);
+        let draining = app
+            .clone()
+            .oneshot(axum::http::Request::get("/sse/system?token=bad").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(draining.status(), StatusCode::SERVICE_UNAVAILABLE);
+        state.draining.store(false, Ordering::SeqCst);
+
+        let app_origins = Router::new().route("/sse/{topic}", get(sse_handler)).with_state(Arc::new(State {
+            origins: Origins::new(["https://app.test"]),
+            ..State::new(redis::Client::open("redis://127.0.0.1:1").unwrap(), "secret".into())
+        }));
+        let request = axum::http::Request::get("/sse/system?token=bad")
+            .header("origin", "https://evil.test")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(app_origins.oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let token = state
+            .jwt
+            .sign(
+                Claims {
+                    id: "alice".into(),
+                    channel: "system".into(),
+                    ..Claims::default()
+                },
+                60,
+            )
+            .await
+            .unwrap();
+        let request = axum::http::Request::get(format!("/sse/system?token={}", token))
+            .header("last-event-id", "1")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        {
+            let ctl = state.ctl.lock().await;
+            let agents = ctl.agents.lock().await;
+            let agent = agents.values().find(|agent| agent.channel == "system").unwrap();
+            assert_eq!(agent.external_id, "alice");
+            assert_eq!(agent.presence_meta()["read_only"], true);
+        }
+        state
+            .ctl
+            .lock()
+            .await
+            .channel_broadcast_json("system", "live", json!({"n": 1}))
+            .await
+            .unwrap();
+
+        let mut body = response.into_body().into_data_stream();
+        let mut text = String::new();
+        while !text.contains("event: live") {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(1), body.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        assert!(!text.contains("id: 1\n"), "already seen by the client");
+        assert!(text.contains("event: tick\ndata: {\"seq\":2}\nid: 2\n"));
+        assert!(text.contains("id: 3\n"));
+        assert!(text.contains("event: live\ndata: {\"n\":1}\n"));
+
+        drop(body);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(state.ctl.lock().await.agents.lock().await.is_empty(), "agent should leave with the stream");
+    }
+}
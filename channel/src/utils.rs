@@ -1,41 +1,108 @@
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use rand::distr::Alphanumeric;
-use rand::{Rng, rng};
+use rand::{rng, Rng};
 use serde::{Deserialize, Serialize};
 
 pub fn random_string(length: usize) -> String {
     rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// `room:*` matches every topic starting with `room:`, other patterns match exactly
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => topic.starts_with(prefix),
+        None => pattern == topic,
+    }
+}
+
+/// `aud`, one audience or several
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// access to the topics matching a pattern, `room:*` or an exact topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopicGrant {
+    pub topic: String,
+    #[serde(default = "default_read")]
+    pub read: bool, // join and receive
+    #[serde(default)]
+    pub write: bool, // push
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>, // the events that can be pushed, any if empty
+}
+
+fn default_read() -> bool {
+    true
+}
+
+impl TopicGrant {
+    pub fn can_push(&self, event: &str) -> bool {
+        self.write && (self.events.is_empty() || self.events.iter().any(|e| e == event))
+    }
+}
+
+/// the first grant matching the topic
+pub fn find_grant<'a>(grants: &'a [TopicGrant], topic: &str) -> Option<&'a TopicGrant> {
+    grants.iter().find(|grant| topic_matches(&grant.topic, topic))
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Claims {
     pub id: String,
     pub channel: String,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>, // revocable by this id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<TopicGrant>, // every topic is granted if empty
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub assigns: serde_json::Map<String, serde_json::Value>, // attached to the agent, in presence metas and `from:` messages
 }
 
-pub async fn generate_jwt(id: String, channel: String, jwt_secret: String, expiration_secs: i64) -> jsonwebtoken::errors::Result<String> {
-    let expiration = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::seconds(expiration_secs))
-        .expect("valid timestamp")
-        .timestamp() as usize;
+impl Claims {
+    /// the first grant matching the topic, a full grant when the token doesn't list topics
+    pub fn grant(&self, topic: &str) -> Option<TopicGrant> {
+        if self.topics.is_empty() {
+            return Some(TopicGrant {
+                topic: topic.to_string(),
+                read: true,
+                write: true,
+                events: vec![],
+            });
+        }
+        find_grant(&self.topics, topic).cloned()
+    }
 
-    let claims = Claims {
-        id: id.clone(),
-        channel: channel.clone(),
-        exp: expiration,
-    };
+    pub fn can_read(&self, topic: &str) -> bool {
+        self.grant(topic).is_some_and(|grant| grant.read)
+    }
 
-    let header = Header::new(Algorithm::HS256);
-    let key = EncodingKey::from_secret(jwt_secret.as_bytes());
-    encode(&header, &claims, &key)
-}
+    pub fn can_push(&self, topic: &str, event: &str) -> bool {
+        self.grant(topic).is_some_and(|grant| grant.can_push(event))
+    }
 
-pub async fn decode_jwt(token: &str, jwt_secret: String) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let decoding_key = DecodingKey::from_secret(jwt_secret.as_bytes());
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.validate_exp = true;
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
 
-    let token_data = decode::<Claims>(token, &decoding_key, &validation)?;
-    Ok(token_data.claims)
+    /// presence metas of the agents joined with the token, `{"assigns": ...}` if there are assigns
+    pub fn presence_meta(&self) -> serde_json::Value {
+        match self.assigns.is_empty() {
+            true => serde_json::json!({}),
+            false => serde_json::json!({"assigns": self.assigns}),
+        }
+    }
 }
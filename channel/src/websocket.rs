@@ -1,8 +1,17 @@
-use crate::channel::{listen_to_redis, Channel, ChannelControl, ChannelError, ChannelMessage};
-use crate::utils::decode_jwt;
+use crate::channel::{listen_to_redis, presence_meta, Channel, ChannelControl, ChannelError, ChannelMessage};
+use crate::handler::{handle_out, terminate, terminate_conn, Handlers, Socket, Sockets};
+use crate::inbound::{Context, MessageLimits, Pipeline, ReceiveError};
+use crate::jwt::Jwt;
+use crate::longpoll::LongPoll;
+use crate::metrics::METRICS;
+use crate::origin::Origins;
+use crate::ratelimit::RateLimit;
+use crate::revocation::is_revoked;
+use crate::serializer::{Frame, Serializer};
+use crate::tls::ClientIdentity;
+use crate::utils::{find_grant, Claims, TopicGrant};
 use futures::SinkExt;
 use futures::StreamExt;
-use itertools::Itertools;
 use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
 use redis::RedisResult;
@@ -12,7 +21,11 @@ use serde_tuple::{Deserialize_tuple, Serialize_tuple};
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Error};
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use tracing::{debug, error, info, warn};
@@ -87,13 +100,13 @@ impl fmt::Display for ServerMessage {
 // request data structures
 // RequestMessage is a message from client through websocket
 // it's deserialized from a JSON array
-#[derive(Debug, Deserialize_tuple)]
-struct RequestMessage {
-    join_ref: Option<String>, // null when it's heartbeat
-    event_ref: String,
-    topic: String, // `channel`
-    event: String,
-    payload: RequestPayload,
+#[derive(Debug, PartialEq, Deserialize_tuple)]
+pub struct RequestMessage {
+    pub join_ref: Option<String>, // null when it's heartbeat
+    pub event_ref: String,
+    pub topic: String, // `channel`
+    pub event: String,
+    pub payload: RequestPayload,
 }
 
 impl Display for RequestMessage {
@@ -108,9 +121,15 @@ impl Display for RequestMessage {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(untagged)]
-enum RequestPayload {
-    Join { token: String },
-    Message { message: String },
+pub enum RequestPayload {
+    Join {
+        token: String,
+        #[serde(flatten)]
+        params: serde_json::Map<String, serde_json::Value>, // the rest of the join payload, for the channel handler
+    },
+    Message {
+        message: String,
+    },
     JsonValue(serde_json::Value), // 这样允许提交的数据只要是JSON 就可以了
 }
 
@@ -118,18 +137,69 @@ pub struct State {
     pub ctl: Mutex<ChannelControl>,
     pub redis_client: redis::Client,
     pub id_length: u8,
-    pub jwt_secret: String,
+    pub jwt: Jwt, // verifies the tokens, signs the ones of `POST /token`
     pub jwt_expiration_secs: i64,
+    pub longpoll: LongPoll,
+    pub node_id: String,                         // identifies this server among the nodes sharing the redis
+    pub api_key: Option<String>,                 // bearer token of the HTTP API, the API is disabled without it
+    pub draining: AtomicBool,                    // shutting down, `/readyz` fails
+    pub handlers: Handlers,                      // in-process channel logic by topic pattern
+    pub sockets: Sockets,                        // handler sockets of the joined agents
+    pub pipeline: Pipeline,                      // inbound middlewares of the client messages
+    pub message_limits: RwLock<MessageLimits>,   // reloaded by `ChannelServer::reload`
+    pub token_refresh_window: Duration,          // agents get `token_expiring` this long before their token expires
+    pub topic_policies: RwLock<Vec<TopicGrant>>, // server-wide, the grants of the tokens can only narrow them
+    pub origins: Origins,                        // of the websocket upgrades, long-poll sessions and SSE subscriptions, any if empty
+    pub rate_limit: Option<Arc<RateLimit>>,      // the middleware of `ChannelServerBuilder::rate_limits`, to reload its limits
 }
 
-impl State {}
+impl State {
+    pub fn new(redis_client: redis::Client, jwt_secret: String) -> Self {
+        State {
+            ctl: Mutex::new(ChannelControl::new(Arc::new(redis_client.clone()))),
+            redis_client,
+            id_length: 8,
+            jwt: Jwt::from_secret(&jwt_secret),
+            jwt_expiration_secs: 259200,
+            longpoll: LongPoll::default(),
+            node_id: nanoid::nanoid!(8).to_string(),
+            api_key: None,
+            draining: AtomicBool::new(false),
+            handlers: Handlers::default(),
+            sockets: Mutex::new(HashMap::new()),
+            pipeline: Pipeline::default(),
+            message_limits: RwLock::new(MessageLimits::default()),
+            token_refresh_window: Duration::from_secs(60),
+            topic_policies: RwLock::new(vec![]),
+            origins: Origins::default(),
+            rate_limit: None,
+        }
+    }
+
+    /// the policy of the topic, if any, and the token both allow joining it
+    pub fn can_read(&self, claims: &Claims, topic: &str) -> bool {
+        find_grant(&self.topic_policies.read().unwrap(), topic).is_none_or(|policy| policy.read) && claims.can_read(topic)
+    }
 
-pub async fn axum_on_connected(ws: axum::extract::ws::WebSocket, state: Arc<State>, user_token: Option<String>) {
+    /// the policy of the topic, if any, and the token the agent joined with both allow pushing the event
+    pub fn can_push(&self, claims: &Claims, topic: &str, event: &str) -> bool {
+        find_grant(&self.topic_policies.read().unwrap(), topic).is_none_or(|policy| policy.can_push(event)) && claims.can_push(topic, event)
+    }
+}
+
+pub async fn axum_on_connected(
+    ws: axum::extract::ws::WebSocket, state: Arc<State>, user_token: Option<String>, serializer: Serializer, remote_addr: Option<SocketAddr>,
+    client_identity: Option<ClientIdentity>,
+) {
     info!("params: {:?}", user_token);
 
     let conn_id = nanoid::nanoid!(8).to_string();
-    state.ctl.lock().await.conn_add_tx(conn_id.clone()).await;
-    info!("AXUM / WS_TX / new connection connected: {}", conn_id);
+    state.ctl.lock().await.conn_add(conn_id.clone(), "websocket", remote_addr).await;
+    if let Some(client_identity) = client_identity {
+        info!("AXUM / connection {} with a client certificate of {}", conn_id, client_identity.name);
+        state.ctl.lock().await.conn_set_identity(&conn_id, client_identity).await;
+    }
+    info!("AXUM / WS_TX / new connection connected: {}, serializer: {}, remote: {:?}", conn_id, serializer, remote_addr);
 
     let (mut ws_tx, mut ws_rx) = ws.split();
 
@@ -142,19 +212,37 @@ pub async fn axum_on_connected(ws: axum::extract::ws::WebSocket, state: Arc<Stat
         let mut conn_rx = ws_tx_state.ctl.lock().await.conn_rx(ws_tx_conn_id.clone()).await.unwrap();
         loop {
             match conn_rx.recv().await {
+                Ok(ChannelMessage::Close { code, reason }) => {
+                    info!("AXUM / WS_TX / conn: {}, closing, code: {}, reason: {}", ws_tx_conn_id, code, reason);
+                    let close_frame = axum::extract::ws::CloseFrame { code, reason: reason.into() };
+                    let _ = ws_tx.send(axum::extract::ws::Message::Close(Some(close_frame))).await;
+                    break;
+                }
                 Ok(channel_message) => {
-                    let ChannelMessage::Reply(reply_message) = channel_message;
-                    let text_result = serde_json::to_string(&reply_message);
-                    if text_result.is_err() {
-                        error!("AXUM / WS_TX / fail to serialize reply message: {}", text_result.err().unwrap());
+                    let received_at = channel_message.received_at();
+                    let Some(reply_message) = channel_message.into_reply() else {
+                        continue;
+                    };
+                    let frame_result = serializer.encode(&reply_message);
+                    if frame_result.is_err() {
+                        error!("AXUM / WS_TX / fail to serialize reply message: {}", frame_result.err().unwrap());
                         break;
                     }
-                    let text = text_result.unwrap();
-                    let sending_result = ws_tx.send(axum::extract::ws::Message::Text(text.into())).await;
+                    let ws_message = match frame_result.unwrap() {
+                        Frame::Text(text) => axum::extract::ws::Message::Text(text.into()),
+                        Frame::Binary(bytes) => axum::extract::ws::Message::Binary(bytes.into()),
+                    };
+                    let sending_result = ws_tx.send(ws_message).await;
                     if sending_result.is_err() {
                         error!("AXUM / WS_TX / websocket tx sending failed: {}", sending_result.err().unwrap());
                         break; // what happend? exit if the connection is lost
                     }
+                    METRICS.message_out(&reply_message.topic, &reply_message.event);
+                    METRICS.delivered("websocket", received_at);
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("AXUM / WS_TX / conn: {}, lagged, {} messages dropped", ws_tx_conn_id, n);
+                    METRICS.lagged("conn", n);
                 }
                 Err(e) => {
                     error!("AXUM / WS_TX / rx error: {:?}", e);
@@ -169,25 +257,29 @@ pub async fn axum_on_connected(ws: axum::extract::ws::WebSocket, state: Arc<Stat
     let ws_rx_user_token = user_token.clone();
     let mut ws_rx_task = tokio::spawn(async move {
         info!("AXUM / WS_RX / websocket rx handling (ws rx =>) ...");
-        let mut redis_conn = ws_rx_state.redis_client.get_multiplexed_async_connection().await.unwrap();
+        // connected again on the first publish if redis is down
+        let redis_conn = match ws_rx_state.redis_client.get_multiplexed_async_connection().await {
+            Ok(redis_conn) => Some(redis_conn),
+            Err(e) => {
+                ReceiveError::Redis(e).report(&ws_rx_conn_id);
+                None
+            }
+        };
 
         // 从 websocket rx 读取所有消息，处理或者分发到各个 channel
-        loop {
-            let ws_msg_opt = ws_rx.next().await;
-            if ws_msg_opt.is_none() {
-                error!("AXUM / WS_RX / ws rx receiving failure");
-                break;
-            }
-            let msg_result = ws_msg_opt.unwrap();
-            if msg_result.is_err() {
-                error!("AXUM / WS_RX / rx error: {:?}", msg_result.err());
-                break;
-            }
-            let msg = msg_result.unwrap();
-            handle_message(ws_rx_state.clone(), ws_rx_user_token.clone(), &ws_rx_conn_id, msg.to_text().unwrap(), &mut redis_conn)
-                .await
-                .unwrap();
+        while let Some(msg_result) = ws_rx.next().await {
+            let frame = match msg_result {
+                Ok(axum::extract::ws::Message::Text(text)) => Frame::Text(text.to_string()),
+                Ok(axum::extract::ws::Message::Binary(bytes)) => Frame::Binary(bytes.to_vec()),
+                Ok(_) => continue, // ping/pong are answered by axum, close ends the stream
+                Err(e) => {
+                    ReceiveError::Transport(e.to_string()).report(&ws_rx_conn_id);
+                    break;
+                }
+            };
+            handle_message(ws_rx_state.clone(), ws_rx_user_token.clone(), &ws_rx_conn_id, serializer, &frame, redis_conn.clone()).await;
         }
+        info!("AXUM / WS_RX / conn: {}, stream ended", ws_rx_conn_id);
     });
 
     // Wait for either task to finish: 一个结束了总是等另外一个
@@ -206,6 +298,7 @@ pub async fn axum_on_connected(ws: axum::extract::ws::WebSocket, state: Arc<Stat
         },
     }
 
+    terminate_conn(&state, &conn_id, "closed").await;
     state.ctl.lock().await.conn_cleanup(conn_id.clone()).await;
     info!("AXUM / CONNECTION CLOSED");
     // phoenix/admin/system 之外，如果是 channel 的最后一个 agent，清理 channel 相关
@@ -228,7 +321,9 @@ pub async fn warp_on_connected(ws: WebSocket, state: Arc<State>) {
 
         let mut conn_rx = ws_state.ctl.lock().await.conn_rx(ws_conn_id.clone()).await.unwrap();
         while let Ok(channel_message) = conn_rx.recv().await {
-            let ChannelMessage::Reply(reply_message) = channel_message;
+            let Some(reply_message) = channel_message.into_reply() else {
+                break; // close
+            };
             let text = serde_json::to_string(&reply_message).unwrap();
             let result = ws_tx.send(warp::ws::Message::text(text)).await;
             if result.is_err() {
@@ -243,19 +338,29 @@ pub async fn warp_on_connected(ws: WebSocket, state: Arc<State>) {
     let mut ws_rx_task = tokio::spawn(async move {
         info!("websocket rx handling (ws rx =>) ...");
 
-        let mut redis_conn = state_clone.redis_client.get_multiplexed_async_connection().await.unwrap();
+        let redis_conn = match state_clone.redis_client.get_multiplexed_async_connection().await {
+            Ok(redis_conn) => Some(redis_conn),
+            Err(e) => {
+                ReceiveError::Redis(e).report(&conn_id_clone);
+                None
+            }
+        };
 
         // 从 websocket rx 读取所有消息，处理或者分发到各个 channel
         while let Some(msg_result) = ws_rx.next().await {
-            if msg_result.is_err() {
-                error!("ws rx receiving failure: {}", msg_result.err().unwrap());
-                break;
-            }
-            let msg = msg_result.unwrap();
-            let text = msg.to_str().unwrap();
-            handle_message(state_clone.clone(), None, &conn_id_clone, text, &mut redis_conn)
-                .await
-                .unwrap();
+            let msg = match msg_result {
+                Ok(msg) => msg,
+                Err(e) => {
+                    ReceiveError::Transport(e.to_string()).report(&conn_id_clone);
+                    break;
+                }
+            };
+            let frame = match msg.to_str() {
+                Ok(text) => Frame::Text(text.to_string()),
+                Err(_) if msg.is_binary() => Frame::Binary(msg.into_bytes().to_vec()),
+                Err(_) => continue, // ping/pong/close
+            };
+            handle_message(state_clone.clone(), None, &conn_id_clone, Serializer::Json, &frame, redis_conn.clone()).await;
         }
     });
 
@@ -275,62 +380,64 @@ pub async fn warp_on_connected(ws: WebSocket, state: Arc<State>) {
     info!("client connection closed");
 }
 
-async fn handle_message(
-    state: Arc<State>, user_token: Option<String>, conn_id: &str, text: &str, redis_conn: &mut redis::aio::MultiplexedConnection,
-) -> RedisResult<()> {
-    let rm_result = serde_json::from_str::<RequestMessage>(text);
-    if rm_result.is_err() {
-        error!("WS_RX / conn: {}, error: {:?}", &conn_id, rm_result.err());
-        // 清理 conn_id 的所有 agent
-        // state.ctl.lock().await.agent_rm(conn_id).await;
-        return Ok(());
-    }
-    let rm: RequestMessage = rm_result.unwrap();
-    let channel_name = &rm.topic;
-    let join_ref = &rm.join_ref;
-    let event_ref = &rm.event_ref;
-    let event = &rm.event;
-    let payload = &rm.payload;
-
-    if channel_name == "phoenix" && event == "heartbeat" {
-        // it continues to publish events to the Redis
-        ok_reply(conn_id, None, event_ref, "phoenix", state.clone()).await;
-
-        // payload 是 {}, 所以这里增加一些信息
-        let message = format!(r#"{{"conn_id": "{}"}}"#, conn_id); // double {{ and }} to escape
-        publish_event(redis_conn, "from:phoenix:heartbeat".to_string(), message).await;
-        // continue;
+/// a frame from the client, errors are handled here, see `ReceiveError`
+pub(crate) async fn handle_message(
+    state: Arc<State>, user_token: Option<String>, conn_id: &str, serializer: Serializer, frame: &Frame, redis_conn: Option<MultiplexedConnection>,
+) {
+    // checked before parsing, the connection is closed with "message too big"; axum refuses bigger websocket messages while
+    // reading them, this check replies to the frames read before a reload lowered the limit and to the other transports
+    if frame.len() > state.message_limits.read().unwrap().max_frame_size {
+        METRICS.limit_exceeded.with_label_values(&["frame"]).inc();
+        receive_failed(&state, conn_id, ReceiveError::FrameTooBig(frame.len())).await;
+        return;
     }
 
-    if event == "phx_join" {
-        // 如果没有channel，创建
+    let rm: RequestMessage = match serializer.decode(frame) {
+        Ok(rm) => rm,
+        Err(e) => {
+            let response = ReceiveError::Malformed(e).report(conn_id);
+            // replied to if the refs and the topic can be read, e.g. a bad payload
+            let refs = match frame {
+                Frame::Text(text) => serde_json::from_str::<serde_json::Value>(text).ok(),
+                Frame::Binary(_) => None,
+            };
+            if let Some((join_ref, event_ref, topic)) = refs
+                .as_ref()
+                .and_then(|value| Some((value.get(0)?, value.get(1)?.as_str()?, value.get(2)?.as_str()?)))
+            {
+                let join_ref = join_ref.as_str().map(String::from);
+                reply(conn_id, join_ref, event_ref, topic, "error", response, state.clone()).await;
+            }
+            return;
+        }
+    };
 
-        // 启动一个新的 relay task (agent rx => conn tx), 需要在agent leave 的时候清除
-        // connection 中每个 join 都会产生这个 task；connection 断掉的时候会自动退出
-        let _relay_task = handle_join(user_token, &rm, state.clone(), conn_id).await;
-        debug!("WS_RX / join processed");
-        // continue;
-    }
+    // heartbeat, join, leave and publishing are the built-in steps of the pipeline
+    let mut ctx = Context::new(state.clone(), conn_id.to_string(), user_token, redis_conn);
+    state.pipeline.run(rm, &mut ctx).await;
+}
 
-    if event == "phx_leave" {
-        handle_leave(state.clone(), conn_id, join_ref.clone(), event_ref, channel_name.clone()).await;
-        debug!("WS_RX / leave processed");
+/// report a failure without a message to reply to, closing the connection if it has a close code
+async fn receive_failed(state: &State, conn_id: &str, e: ReceiveError) {
+    e.report(conn_id);
+    if let Some(code) = e.close_code() {
+        let close = ChannelMessage::Close {
+            code,
+            reason: e.reason().to_string(),
+        };
+        let _ = state.ctl.lock().await.conn_send(conn_id.to_string(), close).await;
     }
-
-    // all events are dispatched to reids
-    // iredis --url redis://localhost:6379 psubscribe 'from*'
-    let redis_topic = format!("from:{}:{}", channel_name, event.clone());
-    let message = serde_json::to_string(&payload).unwrap();
-    publish_event(redis_conn, redis_topic, message).await;
-    Ok(())
 }
 
 // iredis --url redis://localhost:6379 psubscribe 'from*'
-async fn publish_event(redis_conn: &mut redis::aio::MultiplexedConnection, redis_topic: String, message: String) {
-    let publish_result: RedisResult<String> = redis_conn.publish(redis_topic.clone(), message.clone()).await;
+pub(crate) async fn publish_event(redis_conn: &mut redis::aio::MultiplexedConnection, redis_topic: String, message: String) -> RedisResult<()> {
+    let publish_result: RedisResult<i64> = redis_conn.publish(redis_topic.clone(), message.clone()).await;
     if let Err(e) = publish_result {
-        error!("fail to publish to redis: {}", e)
+        METRICS.redis_publish_failures.inc();
+        error!("fail to publish to redis: {}", e);
+        return Err(e);
     }
+    Ok(())
 }
 
 pub fn is_special_channel(ch: &str) -> bool {
@@ -373,31 +480,78 @@ pub async fn launch_channel_redis_listen_task(state: Arc<State>, ctl: &Mutex<Cha
         warn!("LAUNCH_REDIS_TASK / channel {} redis_listen_task already exists", channel_name);
         return;
     }
-    channel.redis_listen_task = Some(tokio::spawn(listen_to_redis(state, channel.tx.clone(), redis_client, channel_name.clone())));
+    channel.redis_listen_task =
+        Some(tokio::spawn(listen_to_redis(state, channel.tx.clone(), channel.history.clone(), redis_client, channel_name.clone())));
     info!("LAUNCH_REDIS_TASK / channel {} redis_listen_task launched", channel_name);
 }
 
 // 添加 agent tx, join channel, spawn agent/conn relay task, ack joining
-async fn handle_join(user_token: Option<String>, rm: &RequestMessage, state: Arc<State>, conn_id: &str) -> Result<JoinHandle<()>, ChannelError> {
-    // 先尝试 join payload 是否包含, 然后看 user_tokne 时候有
+pub(crate) async fn handle_join(
+    user_token: Option<String>, rm: &RequestMessage, state: Arc<State>, conn_id: &str, redis_conn: Option<MultiplexedConnection>,
+) -> Result<JoinHandle<()>, ChannelError> {
+    // 先尝试 join payload 是否包含, 然后看 user_tokne 时候有, a client certificate stands in for a missing token
     let token = match &rm.payload {
-        RequestPayload::Join { token } => Ok(token.clone()),
-        _ => user_token.ok_or_else(|| {
-            error!("JOIN / invalid payload: {:?}", rm.payload);
-            ChannelError::BadToken
-        }),
-    }?;
-    let claims = match decode_jwt(&token, state.jwt_secret.clone()).await {
-        Ok(claims) => claims,
-        Err(e) => {
-            error!("JOIN / fail to decode JWT, {}, {}", e, token);
-            return Err(ChannelError::BadToken);
+        RequestPayload::Join { token, .. } => Some(token.clone()),
+        _ => user_token,
+    };
+    let claims = match token {
+        Some(token) => state
+            .jwt
+            .decode(&token)
+            .await
+            .map_err(|e| error!("JOIN / fail to decode JWT, {}", e))
+            .ok(),
+        None => {
+            let client_identity = state.ctl.lock().await.conn_identity(conn_id).await;
+            if client_identity.is_none() {
+                error!("JOIN / no token or client certificate");
+            }
+            client_identity.map(|client_identity| client_identity.claims())
         }
     };
+    let Some(claims) = claims else {
+        reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", json!({"reason": "invalid_token"}), state.clone()).await;
+        return Err(ChannelError::BadToken);
+    };
 
     debug!("JOIN / claims: {:?}", claims);
 
+    // without redis the revocations are unknown, the join goes on
+    if let Some(mut redis_conn) = redis_conn.clone() {
+        match is_revoked(&mut redis_conn, &claims).await {
+            Ok(false) => {}
+            Ok(true) => {
+                warn!("JOIN / token of {} revoked, jti: {:?}", claims.id, claims.jti);
+                reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", json!({"reason": "token_revoked"}), state.clone()).await;
+                return Err(ChannelError::TokenRevoked);
+            }
+            Err(e) => error!("JOIN / fail to check the revocations: {}", e),
+        }
+    }
+    if !state.can_read(&claims, &rm.topic) {
+        warn!("JOIN / token of {} doesn't grant {}", claims.id, rm.topic);
+        reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &rm.topic, "error", json!({"reason": "unauthorized"}), state.clone()).await;
+        return Err(ChannelError::Unauthorized);
+    }
+
     let channel_name = rm.topic.clone();
+    let agent_id = format!("{}:{}:{}", conn_id, channel_name.clone(), rm.join_ref.clone().unwrap_or_default()); // checked by the pipeline
+
+    // the handler decides first, nothing is created for a rejected join
+    let mut socket = Socket::new(state.clone(), channel_name.clone(), agent_id.clone(), claims.id.clone(), rm.join_ref.clone(), redis_conn);
+    socket.assigns = claims.assigns.clone();
+    socket.claims = Some(claims.clone());
+    let join_payload = serde_json::to_value(&rm.payload).unwrap_or_default();
+    let join_response = match state.handlers.find(&channel_name).join(&channel_name, &join_payload, &mut socket).await {
+        Ok(join_response) => join_response,
+        Err(reason) => {
+            warn!("JOIN / {} rejected by the handler: {}", agent_id, reason);
+            reply(conn_id, rm.join_ref.clone(), &rm.event_ref, &channel_name, "error", reason, state.clone()).await;
+            return Err(ChannelError::JoinRejected);
+        }
+    };
+    state.sockets.lock().await.insert(agent_id.clone(), Arc::new(Mutex::new(socket)));
+
     if is_special_channel(&channel_name) {
         info!("ADD_CH / channel {} is special, ignored", channel_name);
     } else {
@@ -405,7 +559,6 @@ async fn handle_join(user_token: Option<String>, rm: &RequestMessage, state: Arc
         launch_channel_redis_listen_task(state.clone(), &state.ctl, channel_name.clone(), state.redis_client.clone()).await;
     }
 
-    let agent_id = format!("{}:{}:{}", conn_id, channel_name.clone(), rm.join_ref.clone().unwrap());
     let join_ref = rm.join_ref.clone();
     let event_ref = rm.event_ref.clone();
 
@@ -415,16 +568,18 @@ async fn handle_join(user_token: Option<String>, rm: &RequestMessage, state: Arc
         .ctl
         .lock()
         .await
-        .channel_join(&channel_name.clone(), agent_id.to_string(), claims.id.clone())
+        .channel_join_with_meta(&channel_name.clone(), agent_id.to_string(), claims.id.clone(), claims.presence_meta())
         .await
     {
         Ok(_) => {}
         Err(e) => {
             // relay task 在连接断开的时候会发生什么?
             error!("JOIN / fail to join: {}", e);
+            state.sockets.lock().await.remove(&agent_id);
             return Err(e);
         }
     }
+    state.ctl.lock().await.agent_set_claims(&agent_id, claims.clone()).await;
 
     // agent rx 到 conn tx 转发消息
     // 这个需要在 join 完整之前准备好，才不会丢失消息
@@ -438,15 +593,22 @@ async fn handle_join(user_token: Option<String>, rm: &RequestMessage, state: Arc
 
         debug!("R / agent {} => conn {}", local_agent_id.clone(), local_conn_id.clone());
         loop {
-            let message_opt = agent_rx.recv().await;
-            if message_opt.is_err() {
-                error!("R / fail to get message from agent rx: {}", message_opt.err().unwrap());
-                break;
-            }
-            let mut channel_message = message_opt.unwrap();
-            let ChannelMessage::Reply(ref mut reply) = channel_message;
-            reply.join_ref = local_join_ref.clone();
-            let send_result = conn_tx.send(channel_message.clone()); // agent rx => conn tx => conn rx => ws tx
+            let message = match agent_rx.recv().await {
+                Ok(message) => message,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("R / agent {}, lagged, {} messages dropped", local_agent_id, n);
+                    METRICS.lagged("relay", n);
+                    continue;
+                }
+                Err(e) => {
+                    error!("R / fail to get message from agent rx: {}", e);
+                    break;
+                }
+            };
+            let Some(message) = handle_out(&relay_state, &local_agent_id, message).await else {
+                continue; // dropped by the channel handler
+            };
+            let send_result = conn_tx.send(message.with_join_ref(local_join_ref.clone())); // agent rx => conn tx => conn rx => ws tx
             if send_result.is_err() {
                 error!("R / agent {}, conn: {}, sending failure: {:?}, exit ...", &local_agent_id, &local_conn_id, send_result.err().unwrap());
                 break; // fails when there's no reciever, connection lost, stop forwarding
@@ -455,8 +617,12 @@ async fn handle_join(user_token: Option<String>, rm: &RequestMessage, state: Arc
         }
     });
 
-    // phx_reply, 确认 join 事件
-    ok_reply(conn_id, join_ref.clone(), &event_ref, &channel_name, state.clone()).await;
+    // phx_reply, 确认 join 事件, with the handler's reply
+    let mut join_reply = json!({"id": agent_id});
+    if let (Some(join_reply), Some(join_response)) = (join_reply.as_object_mut(), join_response.as_object()) {
+        join_reply.extend(join_response.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    reply(conn_id, join_ref.clone(), &event_ref, &channel_name, "ok", join_reply, state.clone()).await;
     info!("JOIN / acked");
 
     if channel_name == "admin" {
@@ -474,38 +640,104 @@ async fn handle_join(user_token: Option<String>, rm: &RequestMessage, state: Arc
     presence_state(conn_id, join_ref.clone(), &event_ref, &channel_name, state.clone()).await;
 
     // presence diff, broadcast
-    let mut redis_conn = state.redis_client.get_multiplexed_async_connection().await.unwrap();
-    presence_diff(&mut redis_conn, channel_name.clone(), agent_id.clone(), claims.id.clone(), PresenceAction::Join).await;
+    match state.redis_client.get_multiplexed_async_connection().await {
+        Ok(mut redis_conn) => {
+            presence_diff(&mut redis_conn, channel_name.clone(), agent_id.clone(), claims.id.clone(), claims.presence_meta(), PresenceAction::Join)
+                .await
+        }
+        Err(e) => {
+            ReceiveError::Redis(e).report(conn_id);
+        }
+    }
 
     Ok(relay_task)
 }
 
-async fn handle_leave(state: Arc<State>, conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: String) {
-    let agent_id = format!("{}:{}:{}", conn_id, channel_name, join_ref.clone().unwrap());
+/// close an agent like on `phx_leave`, the client gets `phx_close` with `{"reason": reason}`, a presence leave is published
+pub(crate) async fn close_agent(state: &Arc<State>, agent_id: &str, topic: &str, reason: &str) {
+    terminate(state, agent_id, reason).await;
+    let ctl = state.ctl.lock().await;
+    let meta = ctl.agents.lock().await.get(agent_id).map(|agent| agent.meta.clone()).unwrap_or_default();
+    ctl.agent_send_close_with(agent_id, topic, json!({"reason": reason})).await;
+
+    let external_id = ctl.agent_rm(agent_id.to_string()).await;
+    if ctl.channel_leave(topic.to_string(), agent_id.to_string()).await == Ok(0) && !is_special_channel(topic) {
+        ctl.channel_rm(topic.to_string()).await;
+    }
+    drop(ctl);
+
+    if let Some(external_id) = external_id {
+        match state.redis_client.get_multiplexed_async_connection().await {
+            Ok(mut redis_conn) => {
+                presence_diff(&mut redis_conn, topic.to_string(), agent_id.to_string(), external_id, meta, PresenceAction::Leave).await
+            }
+            Err(e) => error!("CLOSE / fail to get redis connection: {}", e),
+        }
+    }
+}
+
+pub(crate) async fn handle_leave(
+    state: Arc<State>, conn_id: &str, join_ref: String, event_ref: &str, channel_name: String,
+) -> Result<(), ReceiveError> {
+    let agent_id = format!("{}:{}:{}", conn_id, channel_name, join_ref);
+    terminate(&state, &agent_id, "leave").await;
+    let meta = state.ctl.lock().await.agents.lock().await.get(&agent_id).map(|agent| agent.meta.clone());
     let external_id_opt = state.ctl.lock().await.agent_rm(agent_id.clone()).await;
-    let agent_count = state
-        .ctl
-        .lock()
-        .await
-        .channel_leave(channel_name.clone(), agent_id.clone())
-        .await
-        .unwrap();
-    if agent_count == 0 && !is_special_channel(&channel_name) {
+    let agent_count = state.ctl.lock().await.channel_leave(channel_name.clone(), agent_id.clone()).await;
+    if agent_count.is_err() || external_id_opt.is_none() {
+        error!("LEAVE / agent {} not found", agent_id);
+        return Err(ReceiveError::NotJoined);
+    }
+    if agent_count == Ok(0) && !is_special_channel(&channel_name) {
         warn!("LEAVE / channel {} is empty, cleaning up ...", channel_name);
         state.ctl.lock().await.channel_rm(channel_name.clone()).await; // 空的 channel 会被清理
     }
-    ok_reply(conn_id, join_ref, event_ref, &channel_name, state.clone()).await;
+    ok_reply(conn_id, Some(join_ref), event_ref, &channel_name, state.clone()).await;
 
-    if external_id_opt.is_none() {
-        error!("LEAVE / agent {} not found", agent_id);
-        return;
-    }
     info!("LEAVE / send presense_diff");
-    let mut redis_conn = state.redis_client.get_multiplexed_async_connection().await.unwrap();
-    presence_diff(&mut redis_conn, channel_name.clone(), agent_id.clone(), external_id_opt.unwrap(), PresenceAction::Leave).await;
+    // the leave is already replied to, a failure here is only logged and counted
+    let mut redis_conn = match state.redis_client.get_multiplexed_async_connection().await {
+        Ok(redis_conn) => redis_conn,
+        Err(e) => {
+            ReceiveError::Redis(e).report(conn_id);
+            return Ok(());
+        }
+    };
+    presence_diff(
+        &mut redis_conn,
+        channel_name.clone(),
+        agent_id.clone(),
+        external_id_opt.unwrap_or_default(),
+        meta.unwrap_or_else(|| json!({})),
+        PresenceAction::Leave,
+    )
+    .await;
+    Ok(())
+}
+
+/// phx_reply with a JSON response, `{"status": status, "response": response}`
+pub(crate) async fn reply(
+    conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: &str, status: &str, response: serde_json::Value, state: Arc<State>,
+) {
+    let reply_message = ServerMessage {
+        join_ref,
+        event_ref: event_ref.to_string(),
+        topic: channel_name.to_string(),
+        event: "phx_reply".to_string(),
+        payload: ServerPayload::ServerJsonValue(json!({"status": status, "response": response})),
+    };
+    if let Err(e) = state
+        .ctl
+        .lock()
+        .await
+        .conn_send(conn_id.to_string(), ChannelMessage::Reply(reply_message))
+        .await
+    {
+        error!("REPLY / conn: {}, fail to send: {}", conn_id, e);
+    }
 }
 
-async fn ok_reply(conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: &str, state: Arc<State>) {
+pub(crate) async fn ok_reply(conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: &str, state: Arc<State>) {
     let response = match join_ref {
         None => Response::Empty {}, // heartbeat
         Some(ref join_ref) => Response::Join {
@@ -522,53 +754,32 @@ async fn ok_reply(conn_id: &str, join_ref: Option<String>, event_ref: &str, chan
             response,
         }),
     };
-    state
+    if let Err(e) = state
         .ctl
         .lock()
         .await
         .conn_send(conn_id.to_string(), ChannelMessage::Reply(join_reply_message))
         .await
-        .unwrap();
+    {
+        error!("REPLY / conn: {}, fail to send: {}", conn_id, e);
+    }
     // let text = serde_json::to_string(&join_reply_message).unwrap();
     // debug!("sent to connection {}: {}", &conn_id, text);
 }
 
 async fn presence_state(conn_id: &str, join_ref: Option<String>, event_ref: &str, channel_name: &str, state: Arc<State>) {
-    let hashed_agents = state
-        .ctl
-        .lock()
-        .await
-        .agents
-        .lock()
-        .await
-        .iter()
-        .filter(|(_, agent)| agent.channel == channel_name)
-        .into_group_map_by(|(_, agent)| &agent.external_id)
-        .into_iter()
-        .map(|(external_id, group)| {
-            (external_id.clone(), {
-                json!({
-                    "metas": group.into_iter()
-                        .map(|(id, _)| json!({ "phx_ref": id }))
-                        .collect::<Vec<_>>()
-                })
-            })
-        })
-        .collect::<HashMap<_, _>>();
+    let presence = state.ctl.lock().await.channel_presence(channel_name).await;
     let reply = ServerMessage {
         join_ref: join_ref.clone(),
         event_ref: event_ref.to_string(),
         topic: channel_name.to_string(),
         event: "presence_state".to_string(),
-        payload: ServerPayload::ServerJsonValue(json!(hashed_agents)),
+        payload: ServerPayload::ServerJsonValue(presence),
     };
-    state
-        .ctl
-        .lock()
-        .await
-        .conn_send(conn_id.to_string(), ChannelMessage::Reply(reply))
-        .await
-        .unwrap();
+    if let Err(e) = state.ctl.lock().await.conn_send(conn_id.to_string(), ChannelMessage::Reply(reply)).await {
+        error!("P_STATE / conn: {}, fail to send: {}", conn_id, e);
+        return;
+    }
     info!("P_STATE / sent");
 }
 
@@ -587,6 +798,7 @@ pub async fn presence_diff_many(redis_conn: &mut MultiplexedConnection, channel_
     let message = serde_json::to_string(&diff).unwrap();
     let publish_result: RedisResult<String> = redis_conn.publish(redis_topic.clone(), message.clone()).await;
     if let Err(e) = publish_result {
+        METRICS.redis_publish_failures.inc();
         error!("P_DIFF_MANY / fail to publish to redis: {}", e)
     } else {
         info!("P_DIFF_MANY / sent, {:?}", action);
@@ -595,12 +807,13 @@ pub async fn presence_diff_many(redis_conn: &mut MultiplexedConnection, channel_
 
 /// broadcast presence_diff oever redis
 pub async fn presence_diff(
-    redis_conn: &mut MultiplexedConnection, channel_name: String, agent_id: String, external_id: String, action: PresenceAction,
+    redis_conn: &mut MultiplexedConnection, channel_name: String, agent_id: String, external_id: String, meta: serde_json::Value,
+    action: PresenceAction,
 ) {
     let items = json!({
         external_id.clone(): {
             "metas": [
-                {"phx_ref": agent_id.clone()}
+                presence_meta(&agent_id, &meta)
             ],
         },
     });
@@ -612,6 +825,7 @@ pub async fn presence_diff(
     let message = serde_json::to_string(&diff).unwrap();
     let publish_result: RedisResult<String> = redis_conn.publish(redis_topic.clone(), message.clone()).await;
     if let Err(e) = publish_result {
+        METRICS.redis_publish_failures.inc();
         error!("P_DIFF / fail to publish to redis: {}", e)
     } else {
         info!("P_DIFF / sent, {:?}", action);
@@ -673,9 +887,10 @@ mod tests {
     use serde::Deserialize;
     use serde_json::json;
     use std::collections::HashSet;
+    use std::fmt::Display;
     use std::sync::Arc;
     use tokio::net::{TcpListener, TcpStream};
-    use tokio::sync::Mutex;
+    use tokio::time::Duration;
     use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
     #[derive(Debug, Deserialize)]
@@ -683,6 +898,7 @@ mod tests {
         #[serde(rename = "userToken")]
         user_token: Option<String>,
 
+        #[allow(dead_code)]
         #[serde(rename = "vsn")]
         version: Option<String>,
     }
@@ -691,18 +907,13 @@ mod tests {
         ws: WebSocketUpgrade, Query(params): Query<WebSocketParams>, AxumState(state): AxumState<Arc<State>>,
     ) -> impl IntoResponse {
         let user_token = params.user_token.clone();
-        ws.on_upgrade(move |socket| axum_on_connected(socket, state, user_token))
+        ws.on_upgrade(move |socket| axum_on_connected(socket, state, user_token, Serializer::default(), None, None))
     }
 
     async fn setup_test_server() -> (String, Arc<State>) {
         let redis_url = "redis://127.0.0.1:6379".to_string();
         let redis_client = redis::Client::open(redis_url.clone()).unwrap();
-        let channel_control = ChannelControl::new(Arc::new(redis_client.clone()));
-        let state = Arc::new(State {
-            ctl: Mutex::new(channel_control),
-            redis_client,
-            jwt_secret: "secret".into(),
-        });
+        let state = Arc::new(State::new(redis_client, "secret".into()));
 
         // Setup channels
         state.ctl.lock().await.channel_add("phoenix".into(), None).await;
@@ -739,6 +950,22 @@ mod tests {
         ws_stream.split()
     }
 
+    /// a phx_join to the system channel with a valid token, an invalid one gets `invalid_token`
+    async fn system_join(join_ref: impl Display, event_ref: impl Display) -> String {
+        let token = Jwt::from_secret("secret")
+            .sign(
+                Claims {
+                    id: "alice".into(),
+                    channel: "system".into(),
+                    ..Claims::default()
+                },
+                60,
+            )
+            .await
+            .unwrap();
+        json!([join_ref.to_string(), event_ref.to_string(), "system", "phx_join", {"token": token}]).to_string()
+    }
+
     #[tokio::test]
     async fn test_ws_websocket_connection() {
         let (addr, _) = setup_test_server().await;
@@ -854,11 +1081,11 @@ mod tests {
         let (mut tx, mut rx) = connect_client(&addr).await;
 
         // Join system channel
-        let join_msg = r#"["1","ref1","system","phx_join",{"token":"test"}]"#;
+        let join_msg = system_join(1, "ref1").await;
         tx.send(Message::text(join_msg)).await.unwrap();
 
         // Wait for join response
-        if let Some(Ok(_)) = rx.next().await {}
+        let _ = tokio::time::timeout(Duration::from_secs(5), rx.next()).await;
 
         // Give time for join to complete
         tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
@@ -953,7 +1180,7 @@ mod tests {
         let (mut tx, mut rx) = connect_client(&addr).await;
 
         // Join system channel
-        let join_msg = r#"["1","ref1","system","phx_join",{"token":"test"}]"#;
+        let join_msg = system_join(1, "ref1").await;
         tx.send(Message::text(join_msg)).await.unwrap();
 
         // Verify join response
@@ -1008,7 +1235,7 @@ mod tests {
             let (mut tx, mut rx) = connect_client(&addr).await;
 
             // Join system channel
-            let join_msg = format!(r#"["{}","ref{}","system","phx_join",{{"token":"test"}}]"#, i, i);
+            let join_msg = system_join(i, format!("ref{}", i)).await;
             tx.send(Message::text(join_msg)).await.unwrap();
 
             // Verify join
@@ -1045,7 +1272,7 @@ mod tests {
 
         // Both clients join system channel
         for (tx, i) in [(&mut tx1, 1), (&mut tx2, 2)] {
-            let join_msg = format!(r#"["{}","ref{}","system","phx_join",{{"token":"test"}}]"#, i, i);
+            let join_msg = system_join(i, format!("ref{}", i)).await;
             tx.send(Message::text(join_msg)).await.unwrap();
 
             // Wait for join response
@@ -1099,6 +1326,13 @@ mod tests {
         let invalid_channel = r#"["1","ref1","nonexistent","phx_join",{"token":"test"}]"#;
         tx.send(Message::text(invalid_channel)).await.unwrap();
 
+        // the token can't be decoded
+        if let Some(Ok(msg)) = rx.next().await {
+            let resp: serde_json::Value = serde_json::from_str(&msg.to_string()).unwrap();
+            assert_eq!(resp[2], "nonexistent");
+            assert_eq!(resp[4]["response"], json!({"reason": "invalid_token"}));
+        }
+
         // Connection should still be alive
         let heartbeat = r#"[null,"1","phoenix","heartbeat",{}]"#;
         tx.send(Message::text(heartbeat)).await.unwrap();
@@ -1116,7 +1350,7 @@ mod tests {
         let (mut tx, mut rx) = connect_client(&addr).await;
 
         // Join system channel
-        let join_msg = r#"["1","ref1","system","phx_join",{"token":"test"}]"#;
+        let join_msg = system_join(1, "ref1").await;
         tx.send(Message::text(join_msg)).await.unwrap();
 
         // Should receive initial join response
@@ -1126,14 +1360,59 @@ mod tests {
             assert_eq!(resp[4]["status"], "ok");
         }
 
-        // Should receive datetime updates
-        if let Some(Ok(msg)) = rx.next().await {
-            let resp: serde_json::Value = serde_json::from_str(&msg.to_string()).unwrap();
+        // Should receive datetime updates, after the presence state; the first one comes after 10 seconds
+        let datetime = tokio::time::timeout(Duration::from_secs(15), async {
+            while let Some(Ok(msg)) = rx.next().await {
+                let resp: serde_json::Value = serde_json::from_str(&msg.to_string()).unwrap();
+                if resp[3] == "datetime" {
+                    return Some(resp);
+                }
+            }
+            None
+        });
+        if let Some(resp) = datetime.await.unwrap() {
             assert_eq!(resp[2], "system");
             assert!(resp[4]["response"]["datetime"].is_string());
         }
     }
 
+    #[tokio::test]
+    async fn test_ws_join_grants() {
+        let (addr, state) = setup_test_server().await;
+        let (mut tx, mut rx) = connect_client(&addr).await;
+        let claims = Claims {
+            id: "alice".into(),
+            topics: serde_json::from_value(json!([{"topic": "system"}])).unwrap(),
+            assigns: json!({"plan": "pro"}).as_object().cloned().unwrap(),
+            ..Default::default()
+        };
+        let token = state.jwt.sign(claims, 60).await.unwrap();
+        let mut next_reply = async || loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), rx.next()).await.unwrap().unwrap().unwrap();
+            let resp: serde_json::Value = serde_json::from_str(&msg.to_string()).unwrap();
+            if resp[3] == "phx_reply" {
+                return resp;
+            }
+        };
+
+        tx.send(Message::text(json!(["1", "1", "streaming", "phx_join", {"token": token}]).to_string()))
+            .await
+            .unwrap();
+        let resp = next_reply().await;
+        assert_eq!(resp[4], json!({"status": "error", "response": {"reason": "unauthorized"}}));
+
+        tx.send(Message::text(json!(["2", "2", "system", "phx_join", {"token": token}]).to_string()))
+            .await
+            .unwrap();
+        let resp = next_reply().await;
+        assert_eq!(resp[4]["status"], "ok");
+        let ctl = state.ctl.lock().await;
+        let agents = ctl.agents.lock().await;
+        let agent = agents.values().find(|agent| agent.channel == "system").unwrap();
+        assert_eq!(agent.presence_meta()["assigns"], json!({"plan": "pro"}));
+        assert_eq!(agent.claims.as_ref().map(|claims| claims.topics.len()), Some(1));
+    }
+
     // #[test]
     // fn test_response_invalid_json() {
     //     // Missing type field
@@ -1173,7 +1452,8 @@ mod tests {
         assert_eq!(
             msg.payload,
             RequestPayload::Join {
-                token: "secret_token".to_string()
+                token: "secret_token".to_string(),
+                params: Default::default(),
             }
         );
     }
@@ -1205,7 +1485,8 @@ mod tests {
         assert_eq!(
             payload,
             RequestPayload::Join {
-                token: "another_token".to_string()
+                token: "another_token".to_string(),
+                params: Default::default(),
             }
         );
 